@@ -3,10 +3,12 @@
 # scriptisto-begin
 # script_src: script.py
 # build_once_cmd: virtualenv -p python3 . && . ./bin/activate && pip install mypy termcolor
-# build_cmd: . ./bin/activate && mypy script.py && python3 -m compileall . && chmod +x ./run.sh
+# build_cmd: . ./bin/activate && mypy script.py && python3 -m compileall .
 # target_bin: ./run.sh
+# staged_build: false # virtualenv records the absolute path of the build directory
 # files:
 #   - path: run.sh
+#     executable: true
 #     content: |
 #       #!/bin/sh
 #       export DIR=$(dirname $0)
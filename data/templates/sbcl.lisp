@@ -2,9 +2,10 @@
 
 ; scriptisto-begin
 ; script_src: script.lisp
-; build_cmd: chmod +x build.lisp && ./build.lisp
+; build_cmd: ./build.lisp
 ; files:
 ;  - path: build.lisp
+;    executable: true
 ;    content: |
 ;     #!/usr/bin/sbcl --script
 ;     (load "script.lisp")
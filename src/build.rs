@@ -13,17 +13,48 @@
 // limitations under the License.
 
 use anyhow::{anyhow, Context, Result};
+use fs2::FileExt;
 use log::debug;
+use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
+use crate::buildlog::{self, BuildLog};
+use crate::cache;
 use crate::cfg;
 use crate::common;
+use crate::metadata::{self, Metadata};
 use crate::opt;
+use crate::progress;
+use crate::sources;
 
 pub const SCRIPTISTO_CACHE_DIR_VAR: &str = "SCRIPTISTO_CACHE_DIR";
 pub const SCRIPTISTO_SOURCE_DIR_VAR: &str = "SCRIPTISTO_SOURCE_DIR";
 pub const SCRIPTISTO_SOURCE_VAR: &str = "SCRIPTISTO_SOURCE";
+// If set, least recently used caches are evicted after each build to fit into this size.
+pub const SCRIPTISTO_CACHE_MAX_SIZE_VAR: &str = "SCRIPTISTO_CACHE_MAX_SIZE";
+// Default timeout of builds, e.g. "10m", for build specs without build_timeout.
+pub const SCRIPTISTO_BUILD_TIMEOUT_VAR: &str = "SCRIPTISTO_BUILD_TIMEOUT";
+// If set, the previous build is run when a staged build fails.
+pub const SCRIPTISTO_BUILD_FALLBACK_VAR: &str = "SCRIPTISTO_BUILD_FALLBACK";
+
+/// How the output of build commands is shown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildOutput {
+    /// Captured and only printed if the build fails.
+    Quiet,
+    /// Captured, with a status line showing the progress, for interactive runs.
+    Progress,
+    /// Output is passed through to stderr.
+    Verbose,
+}
+
+// Trees next to the cache root, see common::cache_state_path().
+pub const LOCKS_TREE: &str = "locks";
+pub const STAGING_TREE: &str = "staging";
+pub const OLD_TREE: &str = "old";
 
 fn docker_prefix(script_cache_path: &Path) -> Result<String> {
     Ok(format!(
@@ -45,26 +76,147 @@ pub fn docker_volume_name(script_cache_path: &Path) -> Result<String> {
     Ok(format!("{}-src", docker_prefix))
 }
 
+// Containers are named, so they can be stopped if the build is aborted.
+fn docker_container_name(script_cache_path: &Path, step: &str) -> Result<String> {
+    let docker_prefix = docker_prefix(script_cache_path)?;
+    Ok(format!(
+        "{}-{}-{}",
+        docker_prefix,
+        std::process::id(),
+        step.replace(' ', "-")
+    ))
+}
+
+// Runs the build steps, recording them in the build metadata and the build log.
+struct StepRunner {
+    metadata: Metadata,
+    log: BuildLog,
+    output: BuildOutput,
+    deadline: Option<progress::Deadline>,
+}
+
+impl StepRunner {
+    fn run(&mut self, name: &str, current_directory: &Path, mut cmd: Command) -> Result<()> {
+        let command = std::iter::once(cmd.get_program())
+            .chain(cmd.get_args())
+            .map(|arg| arg.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(" ");
+        self.log.step_started(name, &command);
+
+        let start = Instant::now();
+        let out = progress::run_step_command(
+            current_directory,
+            &mut cmd,
+            name,
+            self.output,
+            &mut self.log,
+            self.deadline,
+        );
+        let exit_code = out.as_ref().ok().and_then(|out| out.status.code());
+        self.log.step_finished(name, exit_code, start.elapsed());
+        self.metadata.steps.push(metadata::Step {
+            name: name.into(),
+            command,
+            exit_code,
+            duration_secs: start.elapsed().as_secs_f64(),
+        });
+        let out = out?;
+        if !out.status.success() {
+            self.report_failure(name, &out.lines);
+            return Err(match out.status.code() {
+                Some(code) => anyhow!(
+                    "Step {} failed. Command {:?} exit code: {}.",
+                    name,
+                    cmd,
+                    code
+                ),
+                None => anyhow!(
+                    "Step {} failed. Command {:?} terminated by signal.",
+                    name,
+                    cmd
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    // Runs a `docker run` command, killing the container if the step is aborted, as stopping the
+    // docker client leaves it running.
+    fn run_container(
+        &mut self,
+        name: &str,
+        current_directory: &Path,
+        cmd: Command,
+        container_name: &str,
+    ) -> Result<()> {
+        let result = self.run(name, current_directory, cmd);
+        if let Err(e) = &result {
+            if e.downcast_ref::<progress::Aborted>().is_some() {
+                debug!("Killing container {}", container_name);
+                let mut kill_cmd = Command::new("docker");
+                kill_cmd.arg("kill").arg(container_name);
+                let _ = common::run_command(current_directory, kill_cmd, Stdio::piped());
+            }
+        }
+        result
+    }
+
+    // Replays the output of the failed step in the order it was printed. In verbose mode it has
+    // been shown already.
+    fn report_failure(&self, name: &str, lines: &[Vec<u8>]) {
+        let lines = match self.output {
+            BuildOutput::Verbose => &[],
+            _ => lines,
+        };
+        let mut stderr = std::io::stderr().lock();
+        if lines.is_empty() {
+            let _ = writeln!(stderr, "==> {} failed", name);
+            return;
+        }
+        let _ = writeln!(stderr, "==> {} failed, output:", name);
+        for line in lines.iter() {
+            let _ = stderr.write_all(line);
+        }
+        if !lines.last().unwrap().ends_with(b"\n") {
+            let _ = writeln!(stderr);
+        }
+    }
+}
+
 fn docker_create_volume(
+    runner: &mut StepRunner,
     volume_name: &str,
     script_cache_path: &Path,
-    stderr_mode: Stdio,
 ) -> Result<()> {
     let mut build_vol_cmd = Command::new("docker");
     build_vol_cmd.arg("volume").arg("create").arg(volume_name);
-    common::run_command(script_cache_path, build_vol_cmd, stderr_mode)?;
+    runner.run("docker volume create", script_cache_path, build_vol_cmd)?;
     Ok(())
 }
 
+fn docker_image_id(image_name: &str, script_cache_path: &Path) -> Result<String> {
+    let mut cmd = Command::new("docker");
+    cmd.arg("image")
+        .arg("inspect")
+        .arg("--format")
+        .arg("{{.Id}}")
+        .arg(image_name);
+    let out = common::run_command(script_cache_path, cmd, Stdio::piped())?;
+    Ok(String::from_utf8_lossy(&out.stdout).trim().to_string())
+}
+
 fn docker_volume_cmd(
+    runner: &mut StepRunner,
+    name: &str,
     volume_name: &str,
     script_cache_path: &Path,
     run_as_current_user: bool,
     cmd: &str,
-    stderr_mode: Stdio,
 ) -> Result<()> {
+    let container_name = docker_container_name(script_cache_path, name)?;
     let mut vol_cmd = Command::new("docker");
-    vol_cmd.args(["run", "-t", "--rm"]);
+    vol_cmd.args(["run", "-t", "--rm", "--name", &container_name]);
     if run_as_current_user {
         vol_cmd.args(["-u", &format!("{}", users::get_current_uid())]);
     }
@@ -78,52 +230,76 @@ fn docker_volume_cmd(
         "-c",
         cmd,
     ]);
-    common::run_command(script_cache_path, vol_cmd, stderr_mode)?;
+    runner.run_container(name, script_cache_path, vol_cmd, &container_name)?;
+    Ok(())
+}
+
+// Sets the SCRIPTISTO_* variables and build_env, which takes precedence, for a command run on the
+// host.
+fn set_host_build_env(
+    cmd: &mut Command,
+    cfg: &cfg::BuildSpec,
+    script_path: &Path,
+    build_path: &Path,
+) -> Result<()> {
+    let script_dir = script_path
+        .parent()
+        .ok_or_else(|| anyhow!("Failed to look up parent directory of {:?}", script_path))?;
+    cmd.env(SCRIPTISTO_CACHE_DIR_VAR, build_path)
+        .env(SCRIPTISTO_SOURCE_DIR_VAR, script_dir)
+        .env(SCRIPTISTO_SOURCE_VAR, script_path)
+        .envs(&cfg.build_env);
     Ok(())
 }
 
-fn run_build_command<F>(
+fn run_build_command(
     cfg: &cfg::BuildSpec,
     script_path: &Path,
     script_cache_path: &Path,
-    first_run: bool,
+    build_path: &Path,
     build_mode: opt::BuildMode,
-    stderr_mode: F,
-) -> Result<()>
-where
-    F: Fn() -> Stdio,
-{
-    if first_run || build_mode == opt::BuildMode::Full {
-        if let Some(build_once_cmd) = &cfg.build_once_cmd {
+    runner: &mut StepRunner,
+) -> Result<()> {
+    if let Some(build_once_cmd) = &cfg.build_once_cmd {
+        // The one-time step is repeated when the command itself changes, not only on a full build.
+        let build_once_digest = format!("{:x}", md5::compute(build_once_cmd.as_bytes()));
+        if runner.metadata.build_once_digest.as_deref() != Some(build_once_digest.as_str())
+            || build_mode == opt::BuildMode::Full
+        {
             let mut cmd = Command::new("/bin/sh");
             cmd.arg("-c").arg(build_once_cmd);
-            common::run_command(script_cache_path, cmd, stderr_mode())?;
+            set_host_build_env(&mut cmd, cfg, script_path, build_path)?;
+            runner.run("build_once_cmd", build_path, cmd)?;
+        } else {
+            debug!("build_once_cmd is unchanged, skipping it");
         }
+        runner.metadata.build_once_digest = Some(build_once_digest);
     }
 
     if let Some(build_cmd) = &cfg.build_cmd {
         match &cfg.docker_build {
-            // TODO: Do better validation for empty dockerfile, but not-empty docker_build.
-            Some(docker_build) if docker_build.dockerfile.is_some() => {
+            Some(docker_build) => {
                 // Write Dockerfile.
                 let tmp_dockerfile_name = "Dockerfile.scriptisto";
                 common::write_bytes(
-                    script_cache_path,
+                    build_path,
                     &PathBuf::from(&tmp_dockerfile_name),
-                    docker_build.dockerfile.clone().unwrap().as_bytes(),
+                    docker_build.dockerfile.as_bytes(),
                 )?;
 
                 // Create and populate sources volume.
                 let src_docker_volume = docker_volume_name(script_cache_path)?;
 
-                docker_create_volume(&src_docker_volume, script_cache_path, stderr_mode())?;
+                runner.metadata.docker_build = true;
+                docker_create_volume(runner, &src_docker_volume, build_path)?;
 
                 docker_volume_cmd(
+                    runner,
+                    "docker copy sources",
                     &src_docker_volume,
-                    script_cache_path,
+                    build_path,
                     false,
                     "cp -rf /src/* /vol/",
-                    stderr_mode(),
                 )?;
 
                 // Build temporary image.
@@ -148,32 +324,54 @@ where
                     .arg(tmp_dockerfile_name)
                     .arg(".");
 
-                common::run_command(script_cache_path, build_im_cmd, stderr_mode())?;
+                runner.run("docker build", build_path, build_im_cmd)?;
+                runner.metadata.docker_image_id =
+                    Some(docker_image_id(&tmp_docker_image, build_path)?);
 
                 // Build binary in Docker.
+                let container_name = docker_container_name(script_cache_path, "build_cmd")?;
                 let mut cmd = Command::new("docker");
                 cmd.arg("run")
                     .arg("-t")
                     .arg("--rm")
-                    .arg("--env")
-                    .arg(format!(
-                        "{}={}",
+                    .arg("--name")
+                    .arg(&container_name);
+
+                // Paths are the ones in the container, where the mounted cache directory also
+                // stands for the script directory.
+                let mut env = vec![];
+                match &docker_build.src_mount_dir {
+                    Some(src_mount_dir) => {
+                        cmd.arg("-v")
+                            .arg(format!("{}:{}", src_docker_volume, src_mount_dir));
+                        let script_src =
+                            common::normalize_relative_path(Path::new(&cfg.script_src))?;
+                        let container_script_path = Path::new(src_mount_dir).join(script_src);
+                        env.push((SCRIPTISTO_CACHE_DIR_VAR, src_mount_dir.clone()));
+                        env.push((SCRIPTISTO_SOURCE_DIR_VAR, src_mount_dir.clone()));
+                        env.push((
+                            SCRIPTISTO_SOURCE_VAR,
+                            container_script_path.to_string_lossy().to_string(),
+                        ));
+                    }
+                    // Nothing is mounted, the host path is only kept for compatibility.
+                    None => env.push((
                         SCRIPTISTO_SOURCE_VAR,
-                        &script_path.to_string_lossy()
-                    ));
-
-                if let Some(src_mount_dir) = &docker_build.src_mount_dir {
-                    cmd.arg("-v")
-                        .arg(format!("{}:{}", src_docker_volume, src_mount_dir));
+                        script_path.to_string_lossy().to_string(),
+                    )),
+                }
+                let build_env = cfg.build_env.iter().map(|(k, v)| (k.as_str(), v.clone()));
+                for (name, value) in env.into_iter().chain(build_env) {
+                    cmd.arg("--env").arg(format!("{}={}", name, value));
                 }
 
                 cmd.args(docker_build.extra_args.iter())
-                    .arg(tmp_docker_image)
+                    .arg(&tmp_docker_image)
                     .arg("sh")
                     .arg("-c")
                     .arg(build_cmd);
 
-                common::run_command(script_cache_path, cmd, stderr_mode())?;
+                runner.run_container("build_cmd", build_path, cmd, &container_name)?;
 
                 // Extract target_bin back to host.
                 let mut vol_path = PathBuf::from("/vol");
@@ -181,8 +379,10 @@ where
                 let mut src_path = PathBuf::from("/src");
                 src_path.push(&cfg.target_bin);
                 docker_volume_cmd(
+                    runner,
+                    "docker copy target_bin",
                     &src_docker_volume,
-                    script_cache_path,
+                    build_path,
                     true,
                     &format!(
                         "mkdir -p $(dirname {}) && cp -rf {} {}",
@@ -190,11 +390,10 @@ where
                         vol_path.to_string_lossy(),
                         src_path.to_string_lossy(),
                     ),
-                    stderr_mode(),
                 )?;
             }
             // Non-Docker build.
-            _ => {
+            None => {
                 let script_dir = match script_path.parent() {
                     None => {
                         return Err(anyhow!(
@@ -206,38 +405,253 @@ where
                 };
 
                 let mut cmd = Command::new("/bin/sh");
-                cmd.arg("-c")
-                    .arg(build_cmd)
-                    .env(SCRIPTISTO_CACHE_DIR_VAR, script_cache_path)
-                    .env(SCRIPTISTO_SOURCE_DIR_VAR, script_dir)
-                    .env(SCRIPTISTO_SOURCE_VAR, script_path);
+                cmd.arg("-c").arg(build_cmd);
+                set_host_build_env(&mut cmd, cfg, script_path, build_path)?;
 
                 let working_directory = if cfg.build_in_script_dir {
                     script_dir
                 } else {
-                    script_cache_path
+                    build_path
                 };
 
-                common::run_command(working_directory, cmd, stderr_mode())?;
+                runner.run("build_cmd", working_directory, cmd)?;
             }
         }
     }
 
-    common::write_bytes(
-        script_cache_path,
-        &PathBuf::from("scriptisto.metadata"),
-        String::new().as_bytes(),
-    )
-    .context("Cannot write metadata file")?;
+    Ok(())
+}
+
+fn script_dir(script_path: &Path) -> Result<PathBuf> {
+    let full_script_path = script_path
+        .canonicalize()
+        .context("Cannot build full path from given script path")?;
+    Ok(full_script_path
+        .parent()
+        .expect("script_src has no parent directory")
+        .to_path_buf())
+}
 
+// Feeds the files into the digest. Paths are hashed too, so renaming or removing a file also
+// triggers a rebuild.
+fn digest_files(ctx: &mut md5::Context, paths: &[PathBuf]) -> Result<()> {
+    for path in paths {
+        debug!("Hashing additional path {:?}", path);
+        match std::fs::File::open(path) {
+            Ok(mut file) => {
+                ctx.consume(path.to_string_lossy().as_bytes());
+                ctx.consume([0u8]);
+                std::io::copy(&mut file, ctx).context(format!("Cannot read {:?}", path))?;
+                ctx.consume([0u8]);
+            }
+            Err(e) => debug!("Cannot open {:?} for hashing: {:?}", path, e),
+        }
+    }
     Ok(())
 }
 
+// Digest of everything that affects the build output: the script itself, the parsed build spec
+// (which also carries the `files` entries), files copied with `files[].source` and the contents of
+// `extra_src_paths`.
+pub fn input_digest(
+    cfg: &cfg::BuildSpec,
+    script_body: &[u8],
+    script_path: &Path,
+) -> Result<String> {
+    let mut ctx = md5::Context::new();
+    ctx.consume(script_body);
+    ctx.consume([0u8]);
+    ctx.consume(serde_yaml::to_string(cfg).context("Cannot serialize build spec")?);
+    ctx.consume([0u8]);
+    let script_dir = script_dir(script_path)?;
+    digest_files(&mut ctx, &sources::collect_file_sources(cfg, &script_dir))?;
+    digest_files(
+        &mut ctx,
+        &sources::collect_extra_src_files(cfg, &script_dir)?,
+    )?;
+    Ok(format!("{:x}", ctx.finalize()))
+}
+
+/// The absolute path of the target binary inside the cache directory.
+pub fn target_bin_path(cfg: &cfg::BuildSpec, script_cache_path: &Path) -> PathBuf {
+    script_cache_path
+        .join(&cfg.target_bin)
+        .components()
+        .collect()
+}
+
+fn is_up_to_date(
+    previous_metadata: Option<&Metadata>,
+    digest: &str,
+    build_mode: &opt::BuildMode,
+) -> bool {
+    // The digest covers the serialized build spec, so any change to the config triggers a rebuild,
+    // regardless of file modification times.
+    let previous_digest = previous_metadata.and_then(|m| m.input_digest.as_deref());
+    debug!(
+        "Input digest: {}, previous digest: {:?}",
+        digest, previous_digest
+    );
+    previous_digest == Some(digest) && *build_mode == opt::BuildMode::Default
+}
+
+fn open_lock_file(script_cache_path: &Path) -> Result<(std::fs::File, PathBuf)> {
+    let lock_path = common::cache_state_path(script_cache_path, LOCKS_TREE)?;
+    let mut attempts = 0;
+    loop {
+        if let Some(parent) = lock_path.parent() {
+            std::fs::create_dir_all(parent)
+                .context(format!("Cannot create cache directory {:?}", parent))?;
+        }
+        let result = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path);
+        match result {
+            Ok(lock_file) => return Ok((lock_file, lock_path)),
+            // The empty parent directory could have been pruned meanwhile.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && attempts < 3 => attempts += 1,
+            Err(e) => {
+                return Err(e).context(format!("Cannot open lock file {:?}", lock_path));
+            }
+        }
+    }
+}
+
+// Lock files are removed along with their cache, so the file could have been unlinked by its
+// previous holder while waiting for the lock. Such a lock does not count.
+fn is_lock_file_current(lock_file: &std::fs::File, lock_path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (lock_file.metadata(), std::fs::metadata(lock_path)) {
+        (Ok(locked), Ok(current)) => locked.dev() == current.dev() && locked.ino() == current.ino(),
+        _ => false,
+    }
+}
+
+// Takes a lock on the cache directory, waiting for other processes to release it. Builds take it
+// exclusively, while runs hold it shared until the script is executed, so the cache is not removed
+// under them. The lock file lives in its own tree, as staged builds replace the cache directory.
+// The lock is released when the returned file is dropped.
+fn lock_cache_dir(script_cache_path: &Path, exclusive: bool) -> Result<std::fs::File> {
+    loop {
+        let (lock_file, lock_path) = open_lock_file(script_cache_path)?;
+        let locked = if exclusive {
+            FileExt::try_lock_exclusive(&lock_file)
+        } else {
+            FileExt::try_lock_shared(&lock_file)
+        };
+        if locked.is_err() {
+            debug!("Waiting for another process to release {:?}", lock_path);
+            if exclusive {
+                FileExt::lock_exclusive(&lock_file)
+            } else {
+                FileExt::lock_shared(&lock_file)
+            }
+            .context(format!("Cannot lock {:?}", lock_path))?;
+        }
+        if is_lock_file_current(&lock_file, &lock_path) {
+            return Ok(lock_file);
+        }
+        debug!("Lock file {:?} was removed, locking again", lock_path);
+    }
+}
+
+/// Takes an exclusive lock on the cache directory, e.g. to remove it. Returns None if another
+/// process is building or running the script.
+pub fn try_lock_cache_dir(script_cache_path: &Path) -> Result<Option<std::fs::File>> {
+    loop {
+        let (lock_file, lock_path) = open_lock_file(script_cache_path)?;
+        if FileExt::try_lock_exclusive(&lock_file).is_err() {
+            return Ok(None);
+        }
+        if is_lock_file_current(&lock_file, &lock_path) {
+            return Ok(Some(lock_file));
+        }
+    }
+}
+
+/// Removes the lock file of a cache directory, along with its empty parent directories. The lock
+/// must be held exclusively, processes waiting for it then lock a new file.
+pub fn remove_lock_file(script_cache_path: &Path) -> Result<()> {
+    let lock_path = common::cache_state_path(script_cache_path, LOCKS_TREE)?;
+    match std::fs::remove_file(&lock_path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            return Err(e).context(format!("Cannot remove lock file {:?}", lock_path));
+        }
+        _ => {}
+    }
+    common::remove_empty_parents(&lock_path, &common::cache_tree(LOCKS_TREE)?);
+    Ok(())
+}
+
+// Creates a staging directory seeded with the current cache contents, so incremental builds still
+// work.
+fn prepare_staging_dir(script_cache_path: &Path) -> Result<PathBuf> {
+    let staging_path = common::cache_state_path(script_cache_path, STAGING_TREE)?;
+    if staging_path.exists() {
+        common::remove_cache_dir(&staging_path).context(format!(
+            "Cannot remove stale staging directory {:?}",
+            staging_path
+        ))?;
+    }
+    if script_cache_path.exists() {
+        common::copy_dir(script_cache_path, &staging_path)
+            .context("Cannot copy cache directory for a staged build")?;
+    }
+    Ok(staging_path)
+}
+
+// Replaces the cache directory with the successfully built staging directory. Both are swapped
+// atomically, so a script started meanwhile finds either the previous or the new build.
+fn promote_staging_dir(staging_path: &Path, script_cache_path: &Path) -> Result<()> {
+    if !script_cache_path.exists() {
+        return std::fs::rename(staging_path, script_cache_path)
+            .context(format!("Cannot promote {:?}", staging_path));
+    }
+    let old_path = match common::exchange_paths(staging_path, script_cache_path) {
+        Ok(()) => staging_path.to_path_buf(),
+        Err(e) => {
+            // E.g. on file systems without support for it, the cache directory is then missing
+            // for a moment.
+            debug!("Cannot swap {:?} in atomically: {:?}", staging_path, e);
+            let old_path = common::cache_state_path(script_cache_path, OLD_TREE)?;
+            let _ = common::remove_cache_dir(&old_path);
+            if let Some(parent) = old_path.parent() {
+                std::fs::create_dir_all(parent)
+                    .context(format!("Cannot create directory {:?}", parent))?;
+            }
+            std::fs::rename(script_cache_path, &old_path)
+                .context(format!("Cannot move away {:?}", script_cache_path))?;
+            std::fs::rename(staging_path, script_cache_path)
+                .context(format!("Cannot promote {:?}", staging_path))?;
+            old_path
+        }
+    };
+    let _ = common::remove_cache_dir(&old_path);
+    common::remove_empty_parents(staging_path, &common::cache_tree(STAGING_TREE)?);
+    common::remove_empty_parents(&old_path, &common::cache_tree(OLD_TREE)?);
+    Ok(())
+}
+
+fn build_timeout(cfg: &cfg::BuildSpec) -> Result<Option<Duration>> {
+    if cfg.build_timeout.is_some() {
+        return Ok(cfg.build_timeout);
+    }
+    match std::env::var_os(SCRIPTISTO_BUILD_TIMEOUT_VAR) {
+        Some(timeout) => humantime::parse_duration(&timeout.to_string_lossy())
+            .map(Some)
+            .context(format!("Cannot parse {}", SCRIPTISTO_BUILD_TIMEOUT_VAR)),
+        None => Ok(None),
+    }
+}
+
 pub fn perform(
     build_mode: opt::BuildMode,
     script_path: &str,
-    show_logs: bool,
-) -> Result<(cfg::BuildSpec, PathBuf)> {
+    output: BuildOutput,
+    allow_fallback: bool,
+) -> Result<(cfg::BuildSpec, PathBuf, std::fs::File)> {
     let script_path = Path::new(script_path);
 
     let script_body = std::fs::read(script_path).context("Cannot read script file")?;
@@ -247,107 +661,143 @@ pub fn perform(
     ))?;
     debug!("Path: {:?}", script_path);
     debug!("Cache path: {:?}", script_cache_path);
-    let cfg = cfg::BuildSpec::new(&script_body).context("Cannot parse build spec")?;
-
-    let mut metadata_path = script_cache_path.clone();
-    metadata_path.push("scriptisto.metadata");
-    let metadata_modified = common::file_modified(&metadata_path).ok();
-    let script_modified = common::file_modified(script_path).ok();
-
-    // If source file is older than metadata file, also hash other paths. Those could be additional
-    // inputs for the build, for example, which must be considered for triggering a rebuild.
-    let mut additional_paths_max_modified: Option<std::time::SystemTime> = None;
-    if metadata_modified > script_modified {
-        let full_script_path = script_path
-            .canonicalize()
-            .context("Cannot build full path from given script path")?;
-        let script_dir = full_script_path
-            .parent()
-            .expect("script_src has no parent directory");
-
-        let mut num_additional_paths_scanned = 0;
-        for additional_path in cfg.extra_src_paths.iter() {
-            let mut full_additional_path = PathBuf::from(additional_path);
-            if !full_additional_path.is_absolute() {
-                full_additional_path = script_dir.join(additional_path);
-            }
+    let cfg = cfg::BuildSpec::new(&script_body, script_path).context("Cannot parse build spec")?;
+    let digest = input_digest(&cfg, &script_body, script_path)?;
 
-            debug!("Hashing additional path {:?}", full_additional_path);
+    let lock = lock_cache_dir(&script_cache_path, false)?;
+    let previous_metadata = Metadata::read(&script_cache_path);
+    if is_up_to_date(previous_metadata.as_ref(), &digest, &build_mode) {
+        debug!("Already compiled, skipping compilation");
+        return Ok((cfg, script_cache_path, lock));
+    }
+    drop(lock);
 
-            for entry_res in walkdir::WalkDir::new(&full_additional_path)
-                .follow_links(true)
-                .into_iter()
-            {
-                debug!("Checking directory entry {:?}", entry_res);
-                if entry_res.is_err() {
-                    continue;
-                }
-                let entry = entry_res.as_ref().unwrap();
+    let lock = lock_cache_dir(&script_cache_path, true)?;
 
-                let metadata_res = entry.metadata();
-                if metadata_res.is_ok() && metadata_res.as_ref().unwrap().is_file() {
-                    num_additional_paths_scanned += 1;
-                    if num_additional_paths_scanned > 500000 {
-                        panic!("Too many files scanned");
-                    }
-                    let modified_res = metadata_res.as_ref().unwrap().modified();
-                    if modified_res.is_ok()
-                        && (additional_paths_max_modified.is_none()
-                            || *modified_res.as_ref().unwrap()
-                                > additional_paths_max_modified.unwrap())
-                    {
-                        additional_paths_max_modified = Some(*modified_res.as_ref().unwrap());
-
-                        if metadata_modified <= additional_paths_max_modified {
-                            debug!(
-                                "File {:?} is newer than metadata, causing rebuild",
-                                entry.path()
-                            );
-                            break;
-                        }
-                    } else if modified_res.is_err() {
-                        debug!("Cannot get modification time of {:?}", entry.path());
-                    }
-                }
-            }
-
-            if metadata_modified <= additional_paths_max_modified {
-                break;
-            }
-        }
+    // Another invocation could have completed the same build while we were waiting for the lock.
+    let previous_metadata = Metadata::read(&script_cache_path);
+    if is_up_to_date(previous_metadata.as_ref(), &digest, &build_mode) {
+        debug!("Already compiled by another invocation, skipping compilation");
+        return Ok((cfg, script_cache_path, lock));
     }
 
-    let first_run = metadata_modified.is_none();
-    let skip_rebuild = metadata_modified > script_modified
-        && metadata_modified > additional_paths_max_modified
-        && build_mode == opt::BuildMode::Default;
+    // The log is kept in the live cache directory, so it survives a failed staged build.
+    let log = BuildLog::create(&script_cache_path, script_path)?;
 
-    if skip_rebuild {
-        debug!("Already compiled, skipping compilation");
+    let build_path = if cfg.staged_build {
+        prepare_staging_dir(&script_cache_path)?
     } else {
-        for file in cfg.files.iter() {
-            common::write_bytes(
-                &script_cache_path,
-                &PathBuf::from(&file.path),
-                file.content.as_bytes(),
-            )?;
+        if allow_fallback {
+            eprintln!(
+                "Warning: {} has no effect with staged_build: false, as a failed build overwrites the previous one.",
+                SCRIPTISTO_BUILD_FALLBACK_VAR
+            );
+        }
+        script_cache_path.clone()
+    };
+    debug!("Build path: {:?}", build_path);
+
+    // Invalidate the previous build first, so an interrupted build is never considered up to
+    // date, even if the inputs are later reverted.
+    let _ = std::fs::remove_file(build_path.join(metadata::METADATA_FILE_NAME));
+
+    for file in cfg.files.iter() {
+        let target = common::cache_file_path(&build_path, Path::new(&file.path))?;
+        match &file.source {
+            Some(source) => sources::copy_file_source(
+                &script_dir(script_path)?.join(source),
+                &build_path,
+                Path::new(&file.path),
+            )?,
+            None => common::write_bytes(
+                &build_path,
+                Path::new(&file.path),
+                file.content.as_deref().unwrap_or_default().as_bytes(),
+            )?,
         }
+        if file.mode.is_some() || file.executable {
+            common::set_file_mode(&target, file.mode, file.executable)?;
+        }
+    }
 
-        run_build_command(
-            &cfg,
-            &common::script_src_to_absolute(script_path)?,
-            &script_cache_path,
-            first_run,
-            build_mode,
-            || {
-                if show_logs {
-                    Stdio::inherit()
-                } else {
-                    Stdio::piped()
+    if cfg.copy_extra_src_paths {
+        sources::mirror_extra_src_files(&cfg, &script_dir(script_path)?, &build_path)?;
+    }
+
+    let mut runner = StepRunner {
+        metadata: Metadata::new(&target_bin_path(&cfg, &script_cache_path)),
+        log,
+        output,
+        deadline: build_timeout(&cfg)?.map(|timeout| progress::Deadline {
+            at: Instant::now() + timeout,
+            timeout,
+        }),
+    };
+    runner.metadata.build_once_digest = previous_metadata
+        .as_ref()
+        .and_then(|m| m.build_once_digest.clone());
+    let result = run_build_command(
+        &cfg,
+        &common::script_src_to_absolute(script_path)?,
+        &script_cache_path,
+        &build_path,
+        build_mode,
+        &mut runner,
+    )
+    // A signal could have arrived outside of a build step.
+    .and_then(|()| progress::check_interrupted());
+
+    let StepRunner {
+        mut metadata,
+        mut log,
+        ..
+    } = runner;
+    match &result {
+        Ok(()) => metadata.input_digest = Some(digest),
+        Err(e) => log.write(format!("==> Build failed: {:#}\n", e).as_bytes()),
+    }
+    drop(log);
+    metadata.write(&build_path)?;
+
+    if cfg.staged_build {
+        match result {
+            Ok(()) => {
+                buildlog::move_logs(&script_cache_path, &build_path)?;
+                promote_staging_dir(&build_path, &script_cache_path)?
+            }
+            Err(e) => {
+                let _ = common::remove_cache_dir(&build_path);
+                common::remove_empty_parents(&build_path, &common::cache_tree(STAGING_TREE)?);
+                // The cache directory still holds the previous successful build, if any.
+                let has_previous_build = previous_metadata
+                    .map(|m| m.input_digest.is_some())
+                    .unwrap_or(false);
+                // Running the script is not what the user wants after stopping the build.
+                let interrupted = matches!(
+                    e.downcast_ref::<progress::Aborted>(),
+                    Some(progress::Aborted::Signal(_))
+                );
+                if allow_fallback && has_previous_build && !interrupted {
+                    eprintln!(
+                        "Warning: build failed, running the previous build. Error: {:#}",
+                        e
+                    );
+                    return Ok((cfg, script_cache_path, lock));
                 }
-            },
-        )?;
+                return Err(e);
+            }
+        }
+    } else {
+        result?;
+    }
+
+    if let Some(max_size) = std::env::var_os(SCRIPTISTO_CACHE_MAX_SIZE_VAR) {
+        let max_size = opt::ByteSize::from_str(&max_size.to_string_lossy())
+            .context(format!("Cannot parse {}", SCRIPTISTO_CACHE_MAX_SIZE_VAR))?;
+        if let Err(e) = cache::evict_lru(max_size.0, &script_cache_path) {
+            debug!("Cache eviction failed: {:?}", e);
+        }
     }
 
-    Ok((cfg, script_cache_path))
+    Ok((cfg, script_cache_path, lock))
 }
@@ -0,0 +1,121 @@
+// Copyright 2019 The Scriptisto Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use anyhow::{anyhow, Context, Result};
+use log::debug;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+pub const BUILD_LOG_FILE_NAME: &str = "scriptisto.build.log";
+// The current log and this many previous ones are kept.
+const MAX_PREVIOUS_LOGS: usize = 4;
+
+/// Path of the log of the N-th previous build, 0 being the last one.
+pub fn log_path(cache_path: &Path, previous: usize) -> PathBuf {
+    if previous == 0 {
+        cache_path.join(BUILD_LOG_FILE_NAME)
+    } else {
+        cache_path.join(format!("{}.{}", BUILD_LOG_FILE_NAME, previous))
+    }
+}
+
+fn rotate(cache_path: &Path) {
+    for i in (0..MAX_PREVIOUS_LOGS).rev() {
+        let from = log_path(cache_path, i);
+        if from.exists() {
+            if let Err(e) = std::fs::rename(&from, log_path(cache_path, i + 1)) {
+                debug!("Cannot rotate build log {:?}: {:?}", from, e);
+            }
+        }
+    }
+}
+
+/// Moves all build logs to another cache directory, e.g. before it replaces this one.
+pub fn move_logs(from: &Path, to: &Path) -> Result<()> {
+    for i in 0..=MAX_PREVIOUS_LOGS {
+        let (from, to) = (log_path(from, i), log_path(to, i));
+        if from.exists() {
+            std::fs::rename(&from, &to).context(format!("Cannot move build log {:?}", from))?;
+        } else {
+            let _ = std::fs::remove_file(&to);
+        }
+    }
+    Ok(())
+}
+
+/// Combined output of all build steps, kept in the cache directory even if the build fails.
+pub struct BuildLog {
+    file: File,
+}
+
+impl BuildLog {
+    pub fn create(cache_path: &Path, script_path: &Path) -> Result<Self> {
+        std::fs::create_dir_all(cache_path)
+            .context(format!("Cannot create cache directory {:?}", cache_path))?;
+        rotate(cache_path);
+        let path = log_path(cache_path, 0);
+        let file = File::create(&path).context(format!("Cannot create build log {:?}", path))?;
+        let mut log = BuildLog { file };
+        log.write(
+            format!(
+                "scriptisto {} build of {:?} at {}\n",
+                env!("CARGO_PKG_VERSION"),
+                script_path,
+                humantime::format_rfc3339_seconds(SystemTime::now())
+            )
+            .as_bytes(),
+        );
+        Ok(log)
+    }
+
+    // Logging must never fail the build.
+    pub fn write(&mut self, data: &[u8]) {
+        if let Err(e) = self.file.write_all(data) {
+            debug!("Cannot write to build log: {:?}", e);
+        }
+    }
+
+    pub fn step_started(&mut self, name: &str, command: &str) {
+        self.write(format!("==> {}: {}\n", name, command).as_bytes());
+    }
+
+    pub fn step_finished(&mut self, name: &str, exit_code: Option<i32>, duration: Duration) {
+        let result = match exit_code {
+            Some(code) => format!("exit code {}", code),
+            None => "no exit code".into(),
+        };
+        self.write(
+            format!(
+                "==> {}: {} after {:.1}s\n",
+                name,
+                result,
+                duration.as_secs_f64()
+            )
+            .as_bytes(),
+        );
+    }
+}
+
+/// Prints the log of the last build, or of an earlier one.
+pub fn print_log(cache_path: &Path, previous: usize) -> Result<()> {
+    let path = log_path(cache_path, previous);
+    let mut file = File::open(&path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => anyhow!("No build log found at {:?}", path),
+        _ => anyhow!("Cannot open build log {:?}: {}", path, e),
+    })?;
+    std::io::copy(&mut file, &mut std::io::stdout().lock()).context("Cannot print build log")?;
+    Ok(())
+}
@@ -14,10 +14,14 @@
 
 use crate::opt::CacheCommand;
 use anyhow::{anyhow, Context, Result};
+use log::debug;
 use number_prefix::NumberPrefix;
+use serde_derive::Serialize;
 use std::collections::BTreeMap;
+use std::fmt;
 use std::path::{Path, PathBuf};
 use std::process;
+use std::time::{Duration, SystemTime};
 
 use crate::*;
 
@@ -25,56 +29,188 @@ fn print_item(name: &str, value: &str) {
     println!("{:20} {}", format!("{}:", name), value);
 }
 
-fn get_dir_size_lossy(path: &Path) -> String {
-    let size: u64 = walkdir::WalkDir::new(path)
+fn get_dir_size(path: &Path) -> u64 {
+    walkdir::WalkDir::new(path)
         .into_iter()
         .map(|r| {
             r.map(|e| e.metadata().map(|m| m.len()).unwrap_or_default())
                 .unwrap_or_default()
         })
-        .sum();
+        .sum()
+}
 
+fn format_size(size: u64) -> String {
     match NumberPrefix::binary(size as f64) {
         NumberPrefix::Standalone(bytes) => format!("{} bytes", bytes),
         NumberPrefix::Prefixed(prefix, n) => format!("{:.0} {}B", n, prefix),
     }
 }
 
-fn collect_info(script_path: &Path) -> Result<BTreeMap<String, String>> {
+/// An item of "info", shown as text in tables and with its type in the serialization formats.
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+enum InfoValue {
+    Text(String),
+    Bool(bool),
+    Bytes(u64),
+    Steps(Vec<metadata::Step>),
+}
+
+impl From<String> for InfoValue {
+    fn from(s: String) -> Self {
+        InfoValue::Text(s)
+    }
+}
+
+impl From<&str> for InfoValue {
+    fn from(s: &str) -> Self {
+        InfoValue::Text(s.into())
+    }
+}
+
+impl fmt::Display for InfoValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InfoValue::Text(s) => write!(f, "{}", s),
+            InfoValue::Bool(b) => write!(f, "{}", b),
+            InfoValue::Bytes(size) => write!(f, "{}", format_size(*size)),
+            InfoValue::Steps(steps) => {
+                let steps: Vec<String> = steps
+                    .iter()
+                    .map(|step| {
+                        format!(
+                            "{} (exit code: {}, {:.2}s)",
+                            step.name,
+                            step.exit_code
+                                .map(|code| code.to_string())
+                                .unwrap_or_else(|| "none".into()),
+                            step.duration_secs
+                        )
+                    })
+                    .collect();
+                write!(f, "{}", steps.join(", "))
+            }
+        }
+    }
+}
+
+fn collect_info(script_path: &Path) -> Result<BTreeMap<String, InfoValue>> {
     let script_body = std::fs::read(script_path).context("Cannot read script file")?;
     let script_cache_path = common::build_cache_path(script_path).context(format!(
         "Cannot build cache path for script: {:?}",
         script_path
     ))?;
-    let cfg = cfg::BuildSpec::new(&script_body)?;
+    let cfg = cfg::BuildSpec::new(&script_body, script_path)?;
+    let metadata = metadata::Metadata::read(&script_cache_path);
 
     let mut items = BTreeMap::new();
 
     items.insert(
         "cache_path".into(),
-        script_cache_path.to_string_lossy().to_string(),
+        script_cache_path.to_string_lossy().to_string().into(),
+    );
+    items.insert(
+        "dir_size".into(),
+        InfoValue::Bytes(get_dir_size(&script_cache_path)),
+    );
+
+    let digest = build::input_digest(&cfg, &script_body, script_path)?;
+    items.insert(
+        "build_state".into(),
+        match metadata.as_ref().and_then(|m| m.input_digest.as_ref()) {
+            None => "not built",
+            Some(previous_digest) if *previous_digest == digest => "up to date",
+            Some(_) => "stale",
+        }
+        .into(),
+    );
+
+    let target_bin = build::target_bin_path(&cfg, &script_cache_path);
+    items.insert(
+        "target_bin_exists".into(),
+        InfoValue::Bool(target_bin.exists()),
+    );
+    items.insert(
+        "target_bin".into(),
+        target_bin.to_string_lossy().to_string().into(),
+    );
+    items.insert(
+        "target_interpreter".into(),
+        cfg.target_interpreter.clone().unwrap_or_default().into(),
+    );
+    items.insert(
+        "build_in_script_dir".into(),
+        InfoValue::Bool(cfg.build_in_script_dir),
     );
 
     if cfg.docker_build.is_some() {
         items.insert(
             "docker_image".into(),
-            build::docker_image_name(&script_cache_path)?,
+            build::docker_image_name(&script_cache_path)?.into(),
         );
         items.insert(
             "docker_src_volume".into(),
-            build::docker_volume_name(&script_cache_path)?,
+            build::docker_volume_name(&script_cache_path)?.into(),
+        );
+    }
+
+    if let Some(metadata) = metadata {
+        items.insert(
+            "build_status".into(),
+            if metadata.input_digest.is_some() {
+                "success"
+            } else {
+                "failed"
+            }
+            .into(),
+        );
+        items.insert(
+            "build_time".into(),
+            humantime::format_rfc3339_seconds(metadata.build_time())
+                .to_string()
+                .into(),
+        );
+        items.insert(
+            "build_version".into(),
+            metadata.scriptisto_version.clone().into(),
+        );
+        items.insert("build_steps".into(), InfoValue::Steps(metadata.steps));
+        if let Some(input_digest) = metadata.input_digest {
+            items.insert("input_digest".into(), input_digest.into());
+        }
+        if let Some(docker_image_id) = metadata.docker_image_id {
+            items.insert("docker_image_id".into(), docker_image_id.into());
+        }
+    }
+
+    let build_log = buildlog::log_path(&script_cache_path, 0);
+    if build_log.exists() {
+        items.insert(
+            "build_log".into(),
+            build_log.to_string_lossy().to_string().into(),
+        );
+    }
+
+    if let Some(last_used) = metadata::last_used(&script_cache_path) {
+        items.insert(
+            "last_used".into(),
+            humantime::format_rfc3339_seconds(last_used)
+                .to_string()
+                .into(),
         );
-        items.insert("dir_size".into(), get_dir_size_lossy(&script_cache_path));
     }
 
     Ok(items)
 }
 
-pub fn command_get(name: &str, script_path: &Path) -> Result<()> {
+pub fn command_get(name: &str, script_path: &Path, format: opt::OutputFormat) -> Result<()> {
     let items = collect_info(script_path)?;
 
     if let Some(value) = items.get(name) {
-        println!("{}", value);
+        match format {
+            opt::OutputFormat::Table => println!("{}", value),
+            format => common::print_serialized(format, value)?,
+        }
         Ok(())
     } else {
         Err(anyhow!(
@@ -85,41 +221,420 @@ pub fn command_get(name: &str, script_path: &Path) -> Result<()> {
     }
 }
 
-pub fn command_info(script_path: &Path) -> Result<()> {
+pub fn command_log(script_path: &Path, previous: usize) -> Result<()> {
+    let script_cache_path = common::build_cache_path(script_path).context(format!(
+        "Cannot build cache path for script: {:?}",
+        script_path
+    ))?;
+    buildlog::print_log(&script_cache_path, previous)
+}
+
+pub fn command_info(script_path: &Path, format: opt::OutputFormat) -> Result<()> {
     let items = collect_info(script_path)?;
 
-    for (k, v) in items.iter() {
-        print_item(k, v);
+    match format {
+        opt::OutputFormat::Table => {
+            for (k, v) in items.iter() {
+                print_item(k, &v.to_string());
+            }
+        }
+        format => common::print_serialized(format, &items)?,
     }
 
     Ok(())
 }
 
+// Removes the cache directory with everything next to it, including its lock file, and its Docker
+// image and volume. These are removed by name if the metadata records a Docker build, or if there
+// is no metadata to tell, and failures to remove them are ignored silently. Returns false without
+// removing anything if another process is building or running the script.
+fn remove_cache(cache_path: &Path) -> Result<bool> {
+    let _lock = match build::try_lock_cache_dir(cache_path)? {
+        Some(lock) => lock,
+        None => return Ok(false),
+    };
+    let docker_build = metadata::Metadata::read(cache_path)
+        .map(|m| m.docker_build || m.docker_image_id.is_some())
+        .unwrap_or(true);
+    match common::remove_cache_dir(cache_path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            return Err(e).context(format!("Cannot remove cache directory {:?}", cache_path))
+        }
+        _ => {}
+    }
+    common::remove_empty_parents(cache_path, &common::cache_root()?);
+    for tree in [build::STAGING_TREE, build::OLD_TREE] {
+        let path = common::cache_state_path(cache_path, tree)?;
+        let _ = common::remove_cache_dir(&path);
+        common::remove_empty_parents(&path, &common::cache_tree(tree)?);
+    }
+
+    if docker_build {
+        for (kind, name) in [
+            ("image", build::docker_image_name(cache_path)?),
+            ("volume", build::docker_volume_name(cache_path)?),
+        ] {
+            let mut cmd = process::Command::new("docker");
+            cmd.arg(kind).arg("rm").arg(name);
+            let _ =
+                common::run_command_unchecked(Path::new("/"), &mut cmd, process::Stdio::piped());
+        }
+    }
+    build::remove_lock_file(cache_path)?;
+    Ok(true)
+}
+
+// Removes lock files without a cache directory, e.g. of scripts whose cache was removed by hand.
+// A lock file held by a build that has not created the cache directory yet is kept.
+fn prune_lock_files() -> Result<()> {
+    let cache_root = common::cache_root()?;
+    let locks_root = common::cache_tree(build::LOCKS_TREE)?;
+    let lock_paths: Vec<PathBuf> = walkdir::WalkDir::new(&locks_root)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .map(|e| e.into_path())
+        .collect();
+    for lock_path in lock_paths {
+        let cache_path = cache_root.join(lock_path.strip_prefix(&locks_root)?);
+        if cache_path.exists() {
+            continue;
+        }
+        if let Some(_lock) = build::try_lock_cache_dir(&cache_path)? {
+            if !cache_path.exists() {
+                debug!("Removing orphaned lock file {:?}", lock_path);
+                build::remove_lock_file(&cache_path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
 pub fn command_clean(script_path: &Path) -> Result<()> {
-    let items = collect_info(script_path)?;
-    let cache_path = items.get("cache_path").expect("cache_path to exist");
+    // The build spec is not parsed, so scripts that cannot be built, e.g. for a missing
+    // environment variable, can still be cleaned.
+    let cache_path = common::build_cache_path(script_path).context(format!(
+        "Cannot build cache path for script: {:?}",
+        script_path
+    ))?;
+
+    if !remove_cache(&cache_path)? {
+        return Err(anyhow!(
+            "Cache {:?} is in use by another scriptisto process, try again later",
+            cache_path
+        ));
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize, Debug)]
+struct CacheEntry {
+    script_path: PathBuf,
+    script_exists: bool,
+    cache_path: PathBuf,
+    dir_size: u64,
+    build_time: Option<String>,
+    #[serde(serialize_with = "serialize_time")]
+    last_used: Option<SystemTime>,
+    docker_image: Option<String>,
+    docker_src_volume: Option<String>,
+}
+
+fn format_time(time: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(time).to_string()
+}
+
+fn serialize_time<S: serde::Serializer>(
+    time: &Option<SystemTime>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    match time {
+        Some(time) => serializer.serialize_some(&format_time(*time)),
+        None => serializer.serialize_none(),
+    }
+}
 
-    let _ = std::fs::remove_dir_all(cache_path);
+// Finds every cache directory under the cache root, i.e. every directory with a metadata file.
+fn collect_cache_entries() -> Result<Vec<CacheEntry>> {
+    let cache_root = common::cache_root()?;
+    let mut entries = vec![];
 
-    if let Some(docker_image) = items.get("docker_image") {
-        let mut cmd = process::Command::new("docker");
-        cmd.arg("image").arg("rm").arg(docker_image);
-        let _ = common::run_command(&PathBuf::from("/"), cmd, process::Stdio::piped());
+    let mut walker = walkdir::WalkDir::new(&cache_root)
+        .sort_by_file_name()
+        .into_iter();
+    while let Some(entry) = walker.next() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                debug!("Skipping cache entry: {:?}", e);
+                continue;
+            }
+        };
+        if !entry.file_type().is_dir() {
+            continue;
+        }
+        let cache_path = entry.path();
+        if !cache_path.join(metadata::METADATA_FILE_NAME).exists() {
+            continue;
+        }
+        // Build outputs inside a cache directory are not interesting.
+        walker.skip_current_dir();
+
+        let script_path = Path::new("/").join(cache_path.strip_prefix(&cache_root)?);
+        let metadata = metadata::Metadata::read(cache_path);
+        let docker_build = metadata
+            .as_ref()
+            .map(|m| m.docker_build || m.docker_image_id.is_some())
+            .unwrap_or(false);
+        entries.push(CacheEntry {
+            script_exists: script_path.exists(),
+            script_path,
+            cache_path: cache_path.to_path_buf(),
+            dir_size: get_dir_size(cache_path),
+            build_time: metadata.as_ref().map(|m| format_time(m.build_time())),
+            last_used: metadata::last_used(cache_path)
+                .or_else(|| metadata.as_ref().map(|m| m.build_time())),
+            docker_image: if docker_build {
+                Some(build::docker_image_name(cache_path)?)
+            } else {
+                None
+            },
+            docker_src_volume: if docker_build {
+                Some(build::docker_volume_name(cache_path)?)
+            } else {
+                None
+            },
+        });
     }
 
-    if let Some(docker_volume) = items.get("docker_src_volume") {
-        let mut cmd = process::Command::new("docker");
-        cmd.arg("volume").arg("rm").arg(docker_volume);
-        let _ = common::run_command(&PathBuf::from("/"), cmd, process::Stdio::piped());
+    Ok(entries)
+}
+
+pub fn command_list(format: opt::OutputFormat) -> Result<()> {
+    let entries = collect_cache_entries()?;
+
+    match format {
+        opt::OutputFormat::Table => {
+            let rows: Vec<_> = entries
+                .iter()
+                .map(|e| {
+                    vec![
+                        e.script_path.to_string_lossy().to_string(),
+                        if e.script_exists { "yes" } else { "no" }.to_string(),
+                        format_size(e.dir_size),
+                        e.build_time.clone().unwrap_or_default(),
+                        e.last_used.map(format_time).unwrap_or_default(),
+                        e.docker_image.clone().unwrap_or_default(),
+                        e.docker_src_volume.clone().unwrap_or_default(),
+                    ]
+                })
+                .collect();
+            common::print_ascii_table(
+                &[
+                    "Script",
+                    "Exists",
+                    "Size",
+                    "Build Time",
+                    "Last Used",
+                    "Docker Image",
+                    "Docker Volume",
+                ],
+                &rows,
+            );
+        }
+        format => common::print_serialized(format, &entries)?,
     }
 
     Ok(())
 }
 
+// Picks caches to remove, least recently used first, along with the reason for removal. The
+// cache at `keep_cache_path` counts towards the total size, but is never removed.
+fn plan_gc(
+    mut entries: Vec<CacheEntry>,
+    remove_orphans: bool,
+    max_age: Option<Duration>,
+    max_size: Option<u64>,
+    keep_cache_path: Option<&Path>,
+) -> Vec<(CacheEntry, &'static str)> {
+    entries.sort_by_key(|e| e.last_used);
+
+    let now = SystemTime::now();
+    let mut total_size: u64 = entries.iter().map(|e| e.dir_size).sum();
+    let mut plan = vec![];
+
+    for entry in entries {
+        if Some(entry.cache_path.as_path()) == keep_cache_path {
+            continue;
+        }
+        let age = entry.last_used.and_then(|t| now.duration_since(t).ok());
+        let reason = if remove_orphans && !entry.script_exists {
+            "script not found"
+        } else if max_age.is_some() && age > max_age {
+            "not used recently"
+        } else if max_size.map(|max| total_size > max).unwrap_or(false) {
+            "cache size over budget"
+        } else {
+            continue;
+        };
+        total_size -= entry.dir_size;
+        plan.push((entry, reason));
+    }
+
+    plan
+}
+
+pub fn command_gc(max_age: Option<Duration>, max_size: Option<u64>, dry_run: bool) -> Result<()> {
+    let plan = plan_gc(collect_cache_entries()?, true, max_age, max_size, None);
+
+    let mut freed_size: u64 = 0;
+    for (entry, reason) in plan.iter() {
+        if !dry_run && !remove_cache(&entry.cache_path)? {
+            println!("Skipped {:?} ({}, but in use)", entry.script_path, reason);
+            continue;
+        }
+        println!(
+            "{} {:?} ({}, {})",
+            if dry_run { "Would remove" } else { "Removed" },
+            entry.script_path,
+            reason,
+            format_size(entry.dir_size)
+        );
+        freed_size += entry.dir_size;
+    }
+
+    if !dry_run {
+        prune_lock_files()?;
+    }
+
+    println!(
+        "{} {}.",
+        if dry_run { "Would free" } else { "Freed" },
+        format_size(freed_size)
+    );
+    Ok(())
+}
+
+/// Removes least recently used caches until the total size fits into `max_size`. The cache of the
+/// script being run is kept.
+pub fn evict_lru(max_size: u64, keep_cache_path: &Path) -> Result<()> {
+    let plan = plan_gc(
+        collect_cache_entries()?,
+        false,
+        None,
+        Some(max_size),
+        Some(keep_cache_path),
+    );
+    for (entry, reason) in plan.iter() {
+        debug!("Evicting {:?}: {}", entry.cache_path, reason);
+        if !remove_cache(&entry.cache_path)? {
+            debug!("Not evicting {:?}, it is in use", entry.cache_path);
+        }
+    }
+    Ok(())
+}
+
 pub fn command_cache(cmd: CacheCommand) -> Result<()> {
     match cmd {
         CacheCommand::Clean { file } => command_clean(&file),
-        CacheCommand::Get { name, file } => command_get(&name, &file),
-        CacheCommand::Info { file } => command_info(&file),
+        CacheCommand::Get { name, file, format } => {
+            command_get(&name, &file, format.unwrap_or_default())
+        }
+        CacheCommand::Info { file, format } => command_info(&file, format.unwrap_or_default()),
+        CacheCommand::Log { file, previous } => command_log(&file, previous),
+        CacheCommand::List { format } => command_list(format.unwrap_or_default()),
+        CacheCommand::Gc {
+            max_age,
+            max_size,
+            dry_run,
+        } => command_gc(max_age.map(Into::into), max_size.map(|s| s.0), dry_run),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn entry(name: &str, script_exists: bool, dir_size: u64, days_unused: u64) -> CacheEntry {
+        CacheEntry {
+            script_path: PathBuf::from("/scripts").join(name),
+            script_exists,
+            cache_path: PathBuf::from("/cache/scripts").join(name),
+            dir_size,
+            build_time: None,
+            last_used: Some(SystemTime::now() - DAY * days_unused as u32),
+            docker_image: None,
+            docker_src_volume: None,
+        }
+    }
+
+    fn names(plan: &[(CacheEntry, &'static str)]) -> Vec<(String, &'static str)> {
+        plan.iter()
+            .map(|(e, reason)| (e.script_path.to_string_lossy().to_string(), *reason))
+            .collect()
+    }
+
+    #[test]
+    fn plan_gc_removes_orphans() {
+        let entries = vec![entry("a", true, 10, 1), entry("b", false, 10, 1)];
+        assert_eq!(
+            names(&plan_gc(entries, true, None, None, None)),
+            vec![("/scripts/b".to_string(), "script not found")]
+        );
+        let entries = vec![entry("a", true, 10, 1), entry("b", false, 10, 1)];
+        assert!(plan_gc(entries, false, None, None, None).is_empty());
+    }
+
+    #[test]
+    fn plan_gc_removes_caches_not_used_recently() {
+        let entries = vec![
+            entry("a", true, 10, 1),
+            entry("b", true, 10, 40),
+            entry("c", true, 10, 10),
+        ];
+        assert_eq!(
+            names(&plan_gc(entries, true, Some(DAY * 7), None, None)),
+            vec![
+                ("/scripts/b".to_string(), "not used recently"),
+                ("/scripts/c".to_string(), "not used recently"),
+            ]
+        );
+    }
+
+    #[test]
+    fn plan_gc_removes_least_recently_used_over_size() {
+        let entries = vec![
+            entry("a", true, 30, 1),
+            entry("b", true, 30, 3),
+            entry("c", true, 30, 2),
+            entry("d", true, 30, 0),
+        ];
+        assert_eq!(
+            names(&plan_gc(entries, true, None, Some(70), None)),
+            vec![
+                ("/scripts/b".to_string(), "cache size over budget"),
+                ("/scripts/c".to_string(), "cache size over budget"),
+            ]
+        );
+    }
+
+    #[test]
+    fn plan_gc_keeps_cache_of_running_script() {
+        let entries = vec![
+            entry("a", true, 30, 1),
+            entry("b", true, 30, 3),
+            entry("c", true, 30, 2),
+        ];
+        let keep = PathBuf::from("/cache/scripts/b");
+        assert_eq!(
+            names(&plan_gc(entries, false, None, Some(50), Some(&keep))),
+            vec![
+                ("/scripts/c".to_string(), "cache size over budget"),
+                ("/scripts/a".to_string(), "cache size over budget"),
+            ]
+        );
     }
 }
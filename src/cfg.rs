@@ -12,14 +12,25 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use log::debug;
-use serde_derive::Deserialize;
+use serde_derive::{Deserialize, Serialize};
+use serde_yaml::Value;
 use std::cmp::min;
+use std::collections::{BTreeMap, HashMap};
 use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::time::Duration;
 
-#[derive(Deserialize, Debug)]
+use crate::build;
+use crate::common;
+use crate::schema;
+use crate::templates;
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct BuildSpec {
+    pub extends: Option<String>, // name of the template this build spec is based on
     pub script_src: String,
     pub build_cmd: Option<String>,
     pub build_once_cmd: Option<String>,
@@ -33,84 +44,811 @@ pub struct BuildSpec {
     #[serde(default)]
     pub docker_build: Option<DockerBuild>,
     #[serde(default)]
-    pub extra_src_paths: Vec<String>, // paths to directory/file, no wildcards supported
+    pub extra_src_paths: Vec<String>, // paths to directories/files or glob patterns, e.g. "src/**/*.rs"
+    #[serde(default)]
+    pub extra_src_exclude: Vec<String>, // gitignore-style patterns to skip within extra_src_paths, e.g. "target/"
+    #[serde(default)]
+    pub extra_src_gitignore: bool, // skip files ignored by .gitignore/.ignore files when scanning extra_src_paths
+    #[serde(default)]
+    pub copy_extra_src_paths: bool, // copy files from extra_src_paths into the cache directory, keeping their paths relative to the script directory
     #[serde(default)]
     pub build_in_script_dir: bool, // use script directory as working directory of build, not the cache directory (non-Docker build only)
+    #[serde(default = "default_staged_build")]
+    pub staged_build: bool, // build in a copy of the cache directory and swap it in only on success; every rebuild copies the whole cache directory, e.g. a cargo target/; set to false for tools that record absolute paths (e.g. virtualenv)
+    #[serde(default, deserialize_with = "deserialize_timeout")]
+    pub build_timeout: Option<Duration>, // e.g. "10m" or a number of seconds, overrides SCRIPTISTO_BUILD_TIMEOUT
+    #[serde(default, deserialize_with = "deserialize_env")]
+    pub build_env: BTreeMap<String, String>, // environment variables of build_cmd and build_once_cmd, also in Docker builds
 }
 
 fn default_target_bin() -> String {
     "./script".into()
 }
 
-#[derive(Deserialize, Debug)]
+fn default_staged_build() -> bool {
+    true
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct File {
     pub path: String,
-    pub content: String,
+    #[serde(default)]
+    pub content: Option<String>, // either content or source is required
+    #[serde(default, alias = "from")]
+    pub source: Option<String>, // file or directory relative to the script, copied to path instead of content
+    #[serde(default, deserialize_with = "deserialize_mode")]
+    pub mode: Option<u32>, // octal permissions as a quoted string, e.g. "0755"
+    #[serde(default)]
+    pub executable: bool, // add the executable bits on top of mode or the default permissions
+    #[serde(default)]
+    pub append: bool, // with extends, append content to the template's file at the same path instead of replacing it
 }
 
-#[derive(Deserialize, Debug)]
+fn parse_mode(s: &str) -> Result<u32> {
+    let digits = s.strip_prefix("0o").unwrap_or(s);
+    if digits.is_empty() || !digits.bytes().all(|b| (b'0'..=b'7').contains(&b)) {
+        return Err(anyhow!("Invalid octal mode {:?}", s));
+    }
+    let mode = u32::from_str_radix(digits, 8).context(format!("Invalid octal mode {:?}", s))?;
+    if mode > 0o7777 {
+        return Err(anyhow!(
+            "Mode {:?} is out of range, expected at most 7777",
+            s
+        ));
+    }
+    Ok(mode)
+}
+
+// Only strings are accepted: YAML reads an unquoted 0o644 as the number 420 and 644 as 644, the two
+// cannot be told apart afterwards.
+fn deserialize_mode<'de, D>(deserializer: D) -> std::result::Result<Option<u32>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RawMode {
+        Str(String),
+        Int(u64),
+    }
+
+    use serde::de::Error;
+    use serde::Deserialize;
+    match Option::<RawMode>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(RawMode::Str(s)) => parse_mode(&s).map(Some).map_err(D::Error::custom),
+        Some(RawMode::Int(n)) => Err(D::Error::custom(format!(
+            "File mode must be quoted to be read as octal, e.g. mode: \"0755\", got the number {}",
+            n
+        ))),
+    }
+}
+
+// Accepts a human-readable duration, e.g. "1h 30m", or a number of seconds.
+fn deserialize_timeout<'de, D>(deserializer: D) -> std::result::Result<Option<Duration>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RawTimeout {
+        Str(String),
+        Int(u64),
+    }
+
+    use serde::de::Error;
+    use serde::Deserialize;
+    match Option::<RawTimeout>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(RawTimeout::Str(s)) => humantime::parse_duration(&s)
+            .map(Some)
+            .map_err(|e| D::Error::custom(format!("Invalid duration {:?}: {}", s, e))),
+        Some(RawTimeout::Int(n)) => Ok(Some(Duration::from_secs(n))),
+    }
+}
+
+// Accepts any scalar value, so "CGO_ENABLED: 0" does not need to be quoted.
+fn deserialize_env<'de, D>(
+    deserializer: D,
+) -> std::result::Result<BTreeMap<String, String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RawValue {
+        Str(String),
+        Int(i64),
+        Float(f64),
+        Bool(bool),
+    }
+
+    use serde::Deserialize;
+    let env = Option::<BTreeMap<String, RawValue>>::deserialize(deserializer)?;
+    Ok(env
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(name, value)| {
+            let value = match value {
+                RawValue::Str(s) => s,
+                RawValue::Int(n) => n.to_string(),
+                RawValue::Float(f) => f.to_string(),
+                RawValue::Bool(b) => b.to_string(),
+            };
+            (name, value)
+        })
+        .collect())
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct DockerBuild {
-    pub dockerfile: Option<String>,
+    pub dockerfile: String,
     pub src_mount_dir: Option<String>,
     #[serde(default)]
     pub extra_args: Vec<String>,
 }
 
+/// Variables available as `${NAME}` in the build spec. `${env:NAME}` reads an environment
+/// variable. Unknown names are left as is, so shell variables in `build_cmd` keep working, and
+/// `$${` produces a literal `${`. `${CACHE_DIR}` is the final cache directory, in build commands it
+/// stands for the directory the build runs in instead.
+#[derive(Clone)]
+struct Vars {
+    vars: HashMap<&'static str, String>,
+    // Names that are known but cannot be used, with the reason.
+    unavailable: HashMap<&'static str, &'static str>,
+}
+
+impl Vars {
+    fn new(script_path: &Path) -> Result<Self> {
+        let script_path = common::script_src_to_absolute(script_path)?;
+        let file_name =
+            |p: Option<&std::ffi::OsStr>| p.unwrap_or_default().to_string_lossy().to_string();
+        let script_dir = script_path
+            .parent()
+            .ok_or_else(|| anyhow!("Cannot compute parent path of {:?}", script_path))?;
+
+        let mut vars = HashMap::new();
+        vars.insert("SCRIPT_NAME", file_name(script_path.file_name()));
+        vars.insert("SCRIPT_STEM", file_name(script_path.file_stem()));
+        vars.insert("SCRIPT_DIR", script_dir.to_string_lossy().into());
+        vars.insert(
+            "CACHE_DIR",
+            common::build_cache_path(&script_path)?
+                .to_string_lossy()
+                .into(),
+        );
+        vars.insert("ARCH", std::env::consts::ARCH.into());
+        Ok(Vars {
+            vars,
+            unavailable: HashMap::new(),
+        })
+    }
+
+    // Build commands do not necessarily run in the cache directory, e.g. with staged_build, and
+    // get the actual build directory as $SCRIPTISTO_CACHE_DIR, which the shell expands.
+    fn for_commands(&self) -> Vars {
+        let mut vars = self.clone();
+        vars.vars.insert(
+            "CACHE_DIR",
+            format!("\"${}\"", build::SCRIPTISTO_CACHE_DIR_VAR),
+        );
+        vars
+    }
+
+    // Values of build_env are not expanded by a shell, so the build directory cannot be given.
+    fn for_env(&self) -> Vars {
+        let mut vars = self.clone();
+        vars.vars.remove("CACHE_DIR");
+        vars.unavailable.insert(
+            "CACHE_DIR",
+            "it is not the build directory with staged_build, use $SCRIPTISTO_CACHE_DIR in build_cmd instead",
+        );
+        vars
+    }
+
+    fn interpolate(&self, s: &str) -> Result<String> {
+        let mut result = String::with_capacity(s.len());
+        let mut rest = s;
+        while let Some(pos) = rest.find("${") {
+            if rest[..pos].ends_with('$') {
+                result.push_str(&rest[..pos - 1]);
+                result.push_str("${");
+                rest = &rest[pos + 2..];
+                continue;
+            }
+            result.push_str(&rest[..pos]);
+            rest = &rest[pos..];
+            let after = &rest[2..];
+            // Without a closing brace the rest is kept as is.
+            let end = match after.find('}') {
+                Some(end) => end,
+                None => break,
+            };
+            let name = &after[..end];
+            if let Some(env_name) = name.strip_prefix("env:") {
+                let value = std::env::var(env_name).context(format!(
+                    "Cannot interpolate ${{env:{}}}, environment variable is not set",
+                    env_name
+                ))?;
+                result.push_str(&value);
+            } else if let Some(value) = self.vars.get(name) {
+                result.push_str(value);
+            } else if let Some(reason) = self.unavailable.get(name) {
+                return Err(anyhow!(
+                    "${{{}}} cannot be used in build_env, {}",
+                    name,
+                    reason
+                ));
+            } else {
+                result.push_str(&rest[..end + 3]);
+            }
+            rest = &after[end + 1..];
+        }
+        result.push_str(rest);
+        Ok(result)
+    }
+
+    fn interpolate_opt(&self, s: &mut Option<String>) -> Result<()> {
+        if let Some(value) = s {
+            *value = self.interpolate(value)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Clone, Debug)]
 enum ParserState {
     ScriptSource,
     ConfigSource { prefix_len: usize },
 }
 
-impl BuildSpec {
-    pub fn new(script_body: &[u8]) -> Result<Self> {
-        let mut script_src = Vec::new();
-        let reader = BufReader::new(script_body);
-
-        use ParserState::*;
-        let mut state = ParserState::ScriptSource;
-
-        let mut cfg_src = vec![];
-
-        for (line_num, line) in reader.lines().enumerate() {
-            let mut line = line.context(format!("Cannot parse script line: {}", line_num))?;
-            script_src.push(line.clone());
-            state = match state {
-                ScriptSource => {
-                    let sb_start = line.find("scriptisto-begin");
-                    if let Some(pos) = sb_start {
-                        ConfigSource { prefix_len: pos }
-                    } else {
-                        state
-                    }
+// The lines between scriptisto-begin and scriptisto-end, with the comment prefix removed.
+struct ConfigBlock {
+    lines: Vec<String>,
+    first_line: usize, // script line number of the first config line, starting from 1
+}
+
+// Splits the script into its lines and the config block.
+fn extract_config(script_body: &[u8]) -> Result<(Vec<String>, ConfigBlock)> {
+    let mut script_src = Vec::new();
+    let reader = BufReader::new(script_body);
+
+    use ParserState::*;
+    let mut state = ParserState::ScriptSource;
+
+    let mut cfg_src = vec![];
+    let mut first_line = None;
+
+    for (line_num, line) in reader.lines().enumerate() {
+        let mut line = line.context(format!("Cannot parse script line: {}", line_num))?;
+        script_src.push(line.clone());
+        state = match state {
+            ScriptSource => {
+                let sb_start = line.find("scriptisto-begin");
+                if let Some(pos) = sb_start {
+                    first_line.get_or_insert(line_num + 2);
+                    ConfigSource { prefix_len: pos }
+                } else {
+                    state
                 }
-                ConfigSource { prefix_len } => {
-                    line.drain(..min(prefix_len, line.len()));
-                    if line.starts_with("scriptisto-end") {
-                        ScriptSource
-                    } else {
-                        cfg_src.push(line);
-                        state
+            }
+            ConfigSource { prefix_len } => {
+                line.drain(..min(prefix_len, line.len()));
+                if line.starts_with("scriptisto-end") {
+                    ScriptSource
+                } else {
+                    cfg_src.push(line);
+                    state
+                }
+            }
+        };
+    }
+    let first_line =
+        first_line.ok_or_else(|| anyhow!("No 'scriptisto-begin' line found in the script"))?;
+    Ok((
+        script_src,
+        ConfigBlock {
+            lines: cfg_src,
+            first_line,
+        },
+    ))
+}
+
+/// Returns the lines of the config block, with the comment prefix removed, and the script line
+/// number of the first one.
+pub fn config_lines(script_body: &[u8]) -> Result<(Vec<String>, usize)> {
+    let (_, block) = extract_config(script_body)?;
+    Ok((block.lines, block.first_line))
+}
+
+fn parse_config(block: &ConfigBlock) -> Result<Value> {
+    let cfg: Value = serde_yaml::from_str(&block.lines.join("\n")).map_err(|e| {
+        let line = e.location().map(|l| block.first_line + l.line() - 1);
+        let message = schema::remap_error_lines(&e.to_string(), block.first_line);
+        schema::SpecErrors(vec![schema::SpecError::new(
+            line,
+            "Invalid YAML".into(),
+            format!(": {}", message),
+        )])
+    })?;
+    schema::validate_keys(&cfg, &block.lines, block.first_line)?;
+    Ok(cfg)
+}
+
+// Replaces `extends: <template>` with the config of that template, recursively, with the keys
+// of the extending config taking precedence.
+fn resolve_extends(cfg: Value, chain: &mut Vec<String>) -> Result<Value> {
+    resolve_extends_with(cfg, chain, &templates::get_template_contents)
+}
+
+// Reads templates with `load`, so tests do not depend on the installed templates.
+fn resolve_extends_with(
+    cfg: Value,
+    chain: &mut Vec<String>,
+    load: &dyn Fn(&str) -> Result<String>,
+) -> Result<Value> {
+    let name = match cfg.get("extends").and_then(|v| v.as_str()) {
+        Some(name) => name.to_string(),
+        None => return Ok(cfg),
+    };
+    if chain.contains(&name) {
+        return Err(anyhow!(
+            "Template inheritance cycle: {} -> {}",
+            chain.join(" -> "),
+            name
+        ));
+    }
+    chain.push(name.clone());
+    debug!("Extending template {:?}", name);
+
+    let contents = load(&name)?;
+    // Flattened into a message, as the line numbers are not the ones of the script.
+    let base = extract_config(contents.as_bytes())
+        .and_then(|(_, block)| parse_config(&block))
+        .map_err(|e| anyhow!("Cannot parse template {:?}: {:#}", name, e))?;
+    let base = resolve_extends_with(base, chain, load)?;
+    Ok(merge_config(base, cfg))
+}
+
+// Mappings are merged key by key, `files` entries are merged by path, any other value of the
+// extending config replaces the base one. A file replaces the base file with the same path as a
+// whole, unless it has `append: true`, then its content is added to the end of the base content.
+fn merge_config(base: Value, cfg: Value) -> Value {
+    match (base, cfg) {
+        (Value::Mapping(mut base), Value::Mapping(cfg)) => {
+            for (key, value) in cfg {
+                let merged = match base.remove(&key) {
+                    Some(base_value) if key.as_str() == Some("files") => {
+                        merge_files(base_value, value)
                     }
+                    Some(base_value) => merge_config(base_value, value),
+                    None => value,
+                };
+                base.insert(key, merged);
+            }
+            Value::Mapping(base)
+        }
+        (_, cfg) => cfg,
+    }
+}
+
+fn merge_files(base: Value, files: Value) -> Value {
+    match (base, files) {
+        (Value::Sequence(mut base), Value::Sequence(files)) => {
+            for file in files {
+                match base.iter_mut().find(|f| f.get("path") == file.get("path")) {
+                    Some(base_file) if file.get("append") == Some(&Value::Bool(true)) => {
+                        let content = appended_content(base_file, &file);
+                        let mut file = merge_config(base_file.clone(), file);
+                        if let Value::Mapping(file) = &mut file {
+                            file.insert("content".into(), content.into());
+                        }
+                        *base_file = file;
+                    }
+                    Some(base_file) => *base_file = file,
+                    None => base.push(file),
                 }
-            };
+            }
+            Value::Sequence(base)
         }
+        (_, files) => files,
+    }
+}
 
-        let mut build_spec: BuildSpec = serde_yaml::from_str(&cfg_src.join("\n"))
-            .context(format!("Cannot parse config YAML: \n{:#?}", cfg_src))?;
+// The base content followed by the appended one, starting on a new line.
+fn appended_content(base_file: &Value, file: &Value) -> String {
+    let content = |f: &Value| {
+        f.get("content")
+            .and_then(|c| c.as_str())
+            .unwrap_or_default()
+            .to_string()
+    };
+    let mut result = content(base_file);
+    if !result.is_empty() && !result.ends_with('\n') {
+        result.push('\n');
+    }
+    result + &content(file)
+}
+
+impl BuildSpec {
+    pub fn new(script_body: &[u8], script_path: &Path) -> Result<Self> {
+        let (mut script_src, block) = extract_config(script_body)?;
+
+        let cfg = parse_config(&block)?;
+        let cfg = resolve_extends(cfg, &mut vec![])?;
+        let mut build_spec: BuildSpec =
+            serde_yaml::from_value(cfg).context("Invalid build spec")?;
+
+        build_spec.interpolate(&Vars::new(script_path)?)?;
 
         let replace_shebang_with = build_spec.replace_shebang_with.clone();
         if !script_src.is_empty() {
             script_src[0] = replace_shebang_with;
         }
 
+        if let Some(file) = build_spec
+            .files
+            .iter()
+            .find(|f| f.source.is_some() == f.content.is_some())
+        {
+            let line =
+                schema::find_key_line(&block.lines, block.first_line, "path", Some(&file.path));
+            return Err(schema::SpecErrors(vec![schema::SpecError::new(
+                line,
+                format!("File {:?}", file.path),
+                if file.source.is_some() {
+                    " must have either 'content' or 'source', not both".into()
+                } else {
+                    " must have 'content' or 'source', use content: \"\" for an empty file".into()
+                },
+            )])
+            .into());
+        }
+
+        // Everything is written and run inside the cache directory, so these paths must not
+        // escape it.
+        let paths = vec![
+            ("script_src", "script_src", &build_spec.script_src),
+            ("target_bin", "target_bin", &build_spec.target_bin),
+        ];
+        let file_paths = build_spec.files.iter().map(|f| ("files", "path", &f.path));
+        for (name, key, path) in paths.into_iter().chain(file_paths) {
+            if let Err(e) = common::normalize_relative_path(Path::new(path)) {
+                let line = schema::find_key_line(&block.lines, block.first_line, key, Some(path));
+                return Err(schema::SpecErrors(vec![schema::SpecError::new(
+                    line,
+                    format!("Invalid {} path", name),
+                    format!(": {}", e),
+                )])
+                .into());
+            }
+        }
+
+        if let Some(docker_build) = &build_spec.docker_build {
+            if docker_build.dockerfile.trim().is_empty() {
+                return Err(anyhow!("'docker_build.dockerfile' must not be empty"));
+            }
+        }
+
         build_spec.files.push(File {
             path: build_spec.script_src.clone(),
-            content: script_src.join("\n"),
+            content: Some(script_src.join("\n")),
+            source: None,
+            mode: None,
+            executable: false,
+            append: false,
         });
 
         debug!("BuildSpec parsed: {:#?}", build_spec);
 
         Ok(build_spec)
     }
+
+    fn interpolate(&mut self, vars: &Vars) -> Result<()> {
+        let command_vars = vars.for_commands();
+        command_vars.interpolate_opt(&mut self.build_cmd)?;
+        command_vars.interpolate_opt(&mut self.build_once_cmd)?;
+        let env_vars = vars.for_env();
+        for value in self.build_env.values_mut() {
+            *value = env_vars.interpolate(value)?;
+        }
+        self.target_bin = vars.interpolate(&self.target_bin)?;
+        vars.interpolate_opt(&mut self.target_interpreter)?;
+        for file in self.files.iter_mut() {
+            file.path = vars.interpolate(&file.path)?;
+            vars.interpolate_opt(&mut file.content)?;
+            vars.interpolate_opt(&mut file.source)?;
+        }
+        if let Some(docker_build) = self.docker_build.as_mut() {
+            docker_build.dockerfile = vars.interpolate(&docker_build.dockerfile)?;
+            vars.interpolate_opt(&mut docker_build.src_mount_dir)?;
+            for arg in docker_build.extra_args.iter_mut() {
+                *arg = vars.interpolate(arg)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script(cfg: &str) -> String {
+        let mut script = String::from("#!/usr/bin/env scriptisto\n# scriptisto-begin\n");
+        for line in cfg.lines() {
+            script += &format!("# {}\n", line);
+        }
+        script + "# scriptisto-end\necho hello\n"
+    }
+
+    fn parse(cfg: &str) -> Result<BuildSpec> {
+        let dir = tempfile::tempdir().unwrap();
+        let script_path = dir.path().join("script.sh");
+        let script = script(cfg);
+        std::fs::write(&script_path, &script).unwrap();
+        BuildSpec::new(script.as_bytes(), &script_path)
+    }
+
+    fn yaml(s: &str) -> Value {
+        serde_yaml::from_str(s).unwrap()
+    }
+
+    // Resolves extends with templates given as name and build spec.
+    fn extend(cfg: &str, templates: &[(&str, &str)]) -> Result<Value> {
+        let templates: HashMap<String, String> = templates
+            .iter()
+            .map(|(name, cfg)| (name.to_string(), script(cfg)))
+            .collect();
+        let load = |name: &str| {
+            templates
+                .get(name)
+                .cloned()
+                .ok_or_else(|| anyhow!("Template {:?} is not found", name))
+        };
+        resolve_extends_with(yaml(cfg), &mut vec![], &load)
+    }
+
+    #[test]
+    fn merge_config_prefers_extending_keys() {
+        let merged = merge_config(
+            yaml("script_src: main.rs\nbuild_cmd: make\ndocker_build:\n  dockerfile: FROM a\n  src_mount_dir: /src"),
+            yaml("build_cmd: make all\ndocker_build:\n  dockerfile: FROM b"),
+        );
+        assert_eq!(
+            merged,
+            yaml("script_src: main.rs\nbuild_cmd: make all\ndocker_build:\n  dockerfile: FROM b\n  src_mount_dir: /src")
+        );
+    }
+
+    #[test]
+    fn merge_config_merges_files_by_path() {
+        let base = yaml(
+            "files:\n- path: Cargo.toml\n  content: a\n- path: run.sh\n  content: x\n  executable: true",
+        );
+        let merged = merge_config(
+            base,
+            yaml("files:\n- path: run.sh\n  content: y\n- path: new.txt\n  content: z"),
+        );
+        assert_eq!(
+            merged,
+            yaml("files:\n- path: Cargo.toml\n  content: a\n- path: run.sh\n  content: y\n- path: new.txt\n  content: z")
+        );
+    }
+
+    #[test]
+    fn merge_config_appends_file_content() {
+        let base = yaml(
+            "files:\n- path: Cargo.toml\n  content: \"[dependencies]\\nclap=1\"\n  mode: \"0644\"",
+        );
+        let merged = merge_config(
+            base,
+            yaml("files:\n- path: Cargo.toml\n  content: \"rand=1\\n\"\n  append: true"),
+        );
+        assert_eq!(
+            merged,
+            yaml("files:\n- path: Cargo.toml\n  content: \"[dependencies]\\nclap=1\\nrand=1\\n\"\n  mode: \"0644\"\n  append: true")
+        );
+    }
+
+    #[test]
+    fn resolve_extends_merges_nested_templates() {
+        let cfg = extend(
+            "extends: a\nbuild_cmd: script",
+            &[
+                ("a", "extends: b\nbuild_cmd: a\ntarget_bin: ./a"),
+                (
+                    "b",
+                    "script_src: main.sh\nbuild_cmd: b\ntarget_bin: ./b\nbuild_once_cmd: b",
+                ),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            cfg,
+            yaml("extends: a\nscript_src: main.sh\nbuild_cmd: script\ntarget_bin: ./a\nbuild_once_cmd: b")
+        );
+    }
+
+    #[test]
+    fn resolve_extends_detects_cycles() {
+        let err = extend("extends: a", &[("a", "extends: b"), ("b", "extends: a")]).unwrap_err();
+        assert!(format!("{:#}", err).contains("Template inheritance cycle: a -> b -> a"));
+
+        let err = extend("extends: a", &[("a", "extends: a")]).unwrap_err();
+        assert!(format!("{:#}", err).contains("a -> a"));
+    }
+
+    #[test]
+    fn accepts_paths_inside_cache_dir() {
+        let cfg = parse(
+            "script_src: src/main.sh\ntarget_bin: ./target/../script\nfiles:\n - path: ./a/b.txt\n   content: x",
+        )
+        .unwrap();
+        assert_eq!(cfg.files.len(), 2);
+    }
+
+    #[test]
+    fn rejects_files_path_outside_cache_dir() {
+        let err = parse("script_src: main.sh\nfiles:\n - path: ../../../.bashrc\n   content: x")
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("Invalid files path at line 5"));
+
+        assert!(parse("script_src: main.sh\nfiles:\n - path: /tmp/evil\n   content: x").is_err());
+    }
+
+    #[test]
+    fn requires_either_content_or_source() {
+        let err = parse("script_src: main.sh\nfiles:\n - path: a.txt").unwrap_err();
+        assert!(format!("{:#}", err)
+            .contains("File \"a.txt\" at line 5 must have 'content' or 'source'"));
+
+        let err =
+            parse("script_src: main.sh\nfiles:\n - path: a.txt\n   content: x\n   source: b.txt")
+                .unwrap_err();
+        assert!(format!("{:#}", err).contains("not both"));
+
+        let cfg = parse("script_src: main.sh\nfiles:\n - path: a.txt\n   content: \"\"").unwrap();
+        assert_eq!(cfg.files[0].content.as_deref(), Some(""));
+    }
+
+    #[test]
+    fn rejects_script_src_outside_cache_dir() {
+        assert!(parse("script_src: ../main.sh").is_err());
+        assert!(parse("script_src: /tmp/main.sh").is_err());
+        assert!(parse("script_src: src/../../main.sh").is_err());
+    }
+
+    #[test]
+    fn rejects_target_bin_outside_cache_dir() {
+        let err = parse("script_src: main.sh\ntarget_bin: /usr/bin/python3").unwrap_err();
+        assert!(format!("{:#}", err).contains("Invalid target_bin path at line 4"));
+
+        assert!(parse("script_src: main.sh\ntarget_bin: ../script").is_err());
+    }
+
+    fn vars() -> Vars {
+        Vars {
+            vars: HashMap::from([
+                ("SCRIPT_NAME", "hello.sh".to_string()),
+                ("CACHE_DIR", "/cache/hello.sh".to_string()),
+            ]),
+            unavailable: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn interpolate_uses_build_dir_for_cache_dir_in_commands() {
+        let vars = vars();
+        assert_eq!(
+            vars.interpolate("${CACHE_DIR}/x").unwrap(),
+            "/cache/hello.sh/x"
+        );
+        assert_eq!(
+            vars.for_commands()
+                .interpolate("cp x ${CACHE_DIR}/x")
+                .unwrap(),
+            "cp x \"$SCRIPTISTO_CACHE_DIR\"/x"
+        );
+        let err = vars.for_env().interpolate("${CACHE_DIR}/x").unwrap_err();
+        assert!(format!("{:#}", err).contains("cannot be used in build_env"));
+        assert_eq!(
+            vars.for_commands()
+                .interpolate("cp x $${CACHE_DIR}")
+                .unwrap(),
+            "cp x ${CACHE_DIR}"
+        );
+    }
+
+    #[test]
+    fn interpolate_replaces_known_names() {
+        let vars = vars();
+        assert_eq!(
+            vars.interpolate("cp ${SCRIPT_NAME} x/${SCRIPT_NAME}")
+                .unwrap(),
+            "cp hello.sh x/hello.sh"
+        );
+        assert_eq!(vars.interpolate("no variables").unwrap(), "no variables");
+    }
+
+    #[test]
+    fn interpolate_keeps_unknown_names() {
+        let vars = vars();
+        assert_eq!(
+            vars.interpolate("echo ${HOME}/${SCRIPT_NAME} $PATH")
+                .unwrap(),
+            "echo ${HOME}/hello.sh $PATH"
+        );
+    }
+
+    #[test]
+    fn interpolate_escapes_double_dollar() {
+        let vars = vars();
+        assert_eq!(
+            vars.interpolate("echo $${SCRIPT_NAME} ${SCRIPT_NAME}")
+                .unwrap(),
+            "echo ${SCRIPT_NAME} hello.sh"
+        );
+    }
+
+    #[test]
+    fn interpolate_keeps_unclosed_brace() {
+        let vars = vars();
+        assert_eq!(
+            vars.interpolate("cp main.sh script # unmatched ${oops")
+                .unwrap(),
+            "cp main.sh script # unmatched ${oops"
+        );
+        assert_eq!(
+            vars.interpolate("${SCRIPT_NAME} ${SCRIPT_NAME").unwrap(),
+            "hello.sh ${SCRIPT_NAME"
+        );
+    }
+
+    #[test]
+    fn interpolate_reads_environment() {
+        let vars = vars();
+        let name = format!("SCRIPTISTO_TEST_VAR_{}", std::process::id());
+        std::env::set_var(&name, "value");
+        assert_eq!(
+            vars.interpolate(&format!("x=${{env:{}}}", name)).unwrap(),
+            "x=value"
+        );
+        std::env::remove_var(&name);
+        let err = vars
+            .interpolate(&format!("x=${{env:{}}}", name))
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("environment variable is not set"));
+    }
+
+    #[test]
+    fn parse_mode_reads_octal() {
+        assert_eq!(parse_mode("0755").unwrap(), 0o755);
+        assert_eq!(parse_mode("644").unwrap(), 0o644);
+        assert_eq!(parse_mode("0o600").unwrap(), 0o600);
+        assert_eq!(parse_mode("4755").unwrap(), 0o4755);
+        for mode in ["", "0o", "0999", "+755", "rwx", "17777", "0o0o755"] {
+            assert!(parse_mode(mode).is_err(), "{:?} should be rejected", mode);
+        }
+    }
+
+    #[test]
+    fn deserialize_mode_requires_quotes() {
+        let mode = |yaml: &str| {
+            serde_yaml::from_str::<File>(&format!("path: a\n{}", yaml)).map(|file| file.mode)
+        };
+        assert_eq!(mode("mode: \"0755\"").unwrap(), Some(0o755));
+        assert_eq!(mode("mode: '0o644'").unwrap(), Some(0o644));
+        assert_eq!(mode("executable: true").unwrap(), None);
+        // Not a valid YAML number, so read as a string.
+        assert_eq!(mode("mode: 0755").unwrap(), Some(0o755));
+        for yaml in ["mode: 0o644", "mode: 644", "mode: \"0999\""] {
+            assert!(mode(yaml).is_err(), "{:?} should be rejected", yaml);
+        }
+        let err = mode("mode: 0o644").unwrap_err();
+        assert!(err.to_string().contains("must be quoted"));
+    }
 }
@@ -0,0 +1,318 @@
+// Copyright 2019 The Scriptisto Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use anyhow::{anyhow, Context, Result};
+use log::debug;
+use std::collections::HashMap;
+use std::fmt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::cfg;
+use crate::common;
+use crate::schema;
+use crate::templates;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Severity {
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Diagnostic {
+    severity: Severity,
+    line: Option<usize>,
+    message: String,
+}
+
+#[derive(Default)]
+struct Diagnostics(Vec<Diagnostic>);
+
+impl Diagnostics {
+    fn error(&mut self, line: Option<usize>, message: String) {
+        self.0.push(Diagnostic {
+            severity: Severity::Error,
+            line,
+            message,
+        });
+    }
+
+    fn warning(&mut self, line: Option<usize>, message: String) {
+        self.0.push(Diagnostic {
+            severity: Severity::Warning,
+            line,
+            message,
+        });
+    }
+}
+
+struct ConfigLines {
+    lines: Vec<String>,
+    first_line: usize,
+}
+
+impl ConfigLines {
+    fn key(&self, key: &str) -> Option<usize> {
+        schema::find_key_line(&self.lines, self.first_line, key, None)
+    }
+
+    fn key_with_value(&self, key: &str, value: &str) -> Option<usize> {
+        schema::find_key_line(&self.lines, self.first_line, key, Some(value))
+            .or_else(|| self.key(key))
+    }
+}
+
+fn check_shebang(script_body: &[u8], diagnostics: &mut Diagnostics) {
+    let first_line = String::from_utf8_lossy(
+        script_body
+            .split(|b| *b == b'\n')
+            .next()
+            .unwrap_or_default(),
+    )
+    .to_string();
+    if !first_line.starts_with("#!") {
+        diagnostics.warning(
+            Some(1),
+            "The first line is not a shebang, the script can only be run as \"scriptisto <script>\""
+                .into(),
+        );
+    } else if !first_line.contains("scriptisto") {
+        diagnostics.warning(
+            Some(1),
+            format!("The shebang {:?} does not invoke scriptisto", first_line),
+        );
+    }
+}
+
+fn check_build_spec(
+    cfg: &cfg::BuildSpec,
+    lines: &ConfigLines,
+    script_dir: &Path,
+    diagnostics: &mut Diagnostics,
+) {
+    // The script itself is the last entry of `files`, written to `script_src`.
+    let mut paths: HashMap<PathBuf, &str> = HashMap::new();
+    for (i, file) in cfg.files.iter().enumerate() {
+        let is_script = i + 1 == cfg.files.len();
+        let (key, line) = if is_script {
+            ("script_src", lines.key("script_src"))
+        } else {
+            ("files", lines.key_with_value("path", &file.path))
+        };
+        // Paths outside of the cache directory are already rejected by the parser.
+        if let Ok(path) = common::normalize_relative_path(Path::new(&file.path)) {
+            if let Some(other) = paths.insert(path, &file.path) {
+                diagnostics.error(
+                    line,
+                    format!(
+                        "Path {:?} in {} collides with files entry {:?}",
+                        file.path, key, other
+                    ),
+                );
+            }
+        }
+        if let Some(source) = &file.source {
+            if !script_dir.join(source).exists() {
+                diagnostics.error(
+                    lines.key_with_value("source", source),
+                    format!(
+                        "Source {:?} of files entry {:?} does not exist",
+                        source, file.path
+                    ),
+                );
+            }
+        }
+    }
+
+    match (&cfg.build_cmd, &cfg.docker_build) {
+        (None, Some(_)) => diagnostics.warning(
+            lines.key("docker_build"),
+            "docker_build has no effect without build_cmd".into(),
+        ),
+        (None, None) => {
+            let target_bin = common::normalize_relative_path(Path::new(&cfg.target_bin)).ok();
+            if target_bin.map(|p| !paths.contains_key(&p)).unwrap_or(false) {
+                diagnostics.warning(
+                    lines.key("target_bin"),
+                    format!(
+                        "There is no build_cmd and target_bin {:?} is not one of the files",
+                        cfg.target_bin
+                    ),
+                );
+            }
+        }
+        _ => {}
+    }
+
+    if let Some(docker_build) = &cfg.docker_build {
+        match &docker_build.src_mount_dir {
+            Some(dir) if !dir.starts_with('/') => diagnostics.error(
+                lines.key("src_mount_dir"),
+                format!(
+                    "docker_build.src_mount_dir {:?} must be an absolute path in the container",
+                    dir
+                ),
+            ),
+            Some(_) => {}
+            None => diagnostics.warning(
+                lines.key("docker_build"),
+                "Without docker_build.src_mount_dir the build output cannot be copied back from the container".into(),
+            ),
+        }
+        if cfg.build_in_script_dir {
+            diagnostics.warning(
+                lines.key("build_in_script_dir"),
+                "build_in_script_dir is ignored for Docker builds".into(),
+            );
+        }
+    }
+
+    if cfg.extra_src_paths.is_empty() {
+        for (key, is_set) in [
+            ("extra_src_exclude", !cfg.extra_src_exclude.is_empty()),
+            ("extra_src_gitignore", cfg.extra_src_gitignore),
+            ("copy_extra_src_paths", cfg.copy_extra_src_paths),
+        ] {
+            if is_set {
+                diagnostics.warning(
+                    lines.key(key),
+                    format!("{} has no effect without extra_src_paths", key),
+                );
+            }
+        }
+    }
+}
+
+fn check_script(script_path: &Path, script_body: &[u8]) -> Diagnostics {
+    let mut diagnostics = Diagnostics::default();
+    check_shebang(script_body, &mut diagnostics);
+
+    let cfg = match cfg::BuildSpec::new(script_body, script_path) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            match e.downcast_ref::<schema::SpecErrors>() {
+                Some(errors) => {
+                    for error in errors.0.iter() {
+                        diagnostics.error(error.line, error.message());
+                    }
+                }
+                None => diagnostics.error(None, format!("{:#}", e)),
+            }
+            return diagnostics;
+        }
+    };
+    let lines = match cfg::config_lines(script_body) {
+        Ok((lines, first_line)) => ConfigLines { lines, first_line },
+        Err(e) => {
+            diagnostics.error(None, format!("{:#}", e));
+            return diagnostics;
+        }
+    };
+    let script_dir = script_path.parent().unwrap_or(Path::new("/"));
+    check_build_spec(&cfg, &lines, script_dir, &mut diagnostics);
+    diagnostics
+}
+
+fn check_file(path: &Path) -> Result<Diagnostics> {
+    let script_path = path
+        .canonicalize()
+        .context(format!("Cannot find script {:?}", path))?;
+    let script_body = std::fs::read(&script_path).context("Cannot read script file")?;
+    let mut diagnostics = check_script(&script_path, &script_body);
+
+    let mode = std::fs::metadata(&script_path)?.permissions().mode();
+    if mode & 0o111 == 0 {
+        diagnostics.warning(None, "The script is not executable".into());
+    }
+    Ok(diagnostics)
+}
+
+// Templates are checked from a temporary copy, as paths in the build spec are resolved relative
+// to the script.
+fn check_templates(mut report: impl FnMut(&str, Diagnostics)) -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("scriptisto-check-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).context(format!("Cannot create directory {:?}", dir))?;
+    let result = (|| {
+        for (filename, contents) in templates::get_template_files()? {
+            let path = dir.join(&filename);
+            std::fs::write(&path, &contents).context(format!("Cannot write {:?}", path))?;
+            report(
+                &format!("template {}", filename),
+                check_script(&path, contents.as_bytes()),
+            );
+        }
+        Ok(())
+    })();
+    if let Err(e) = std::fs::remove_dir_all(&dir) {
+        debug!("Cannot remove {:?}: {:?}", dir, e);
+    }
+    result
+}
+
+pub fn command_check(files: Vec<PathBuf>, check_all_templates: bool) -> Result<()> {
+    let mut num_errors = 0;
+    let mut num_warnings = 0;
+    let mut report = |name: &str, diagnostics: Diagnostics| {
+        for diagnostic in diagnostics.0 {
+            match diagnostic.severity {
+                Severity::Error => num_errors += 1,
+                Severity::Warning => num_warnings += 1,
+            }
+            let location = match diagnostic.line {
+                Some(line) => format!("{}:{}", name, line),
+                None => name.to_string(),
+            };
+            println!(
+                "{}: {}: {}",
+                location, diagnostic.severity, diagnostic.message
+            );
+        }
+    };
+
+    // A file that cannot be read is reported like any other error, the other files are still
+    // checked.
+    for file in files.iter() {
+        let diagnostics = check_file(file).unwrap_or_else(|e| {
+            let mut diagnostics = Diagnostics::default();
+            diagnostics.error(None, format!("{:#}", e));
+            diagnostics
+        });
+        report(&file.to_string_lossy(), diagnostics);
+    }
+    if check_all_templates {
+        check_templates(&mut report)?;
+    }
+
+    if num_errors > 0 {
+        return Err(anyhow!(
+            "Found {} error(s) and {} warning(s)",
+            num_errors,
+            num_warnings
+        ));
+    }
+    if num_warnings > 0 {
+        println!("Found {} warning(s)", num_warnings);
+    }
+    Ok(())
+}
@@ -16,9 +16,11 @@ use anyhow::{anyhow, Context, Result};
 use log::debug;
 use std::fs::File;
 use std::io::Write;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::process::{Command, Stdio};
 
+use crate::opt::OutputFormat;
+
 pub fn script_src_to_absolute(script_src: &Path) -> Result<PathBuf> {
     let script_src_str = script_src.to_string_lossy();
     if !script_src_str.starts_with(['.', '/']) {
@@ -30,23 +32,184 @@ pub fn script_src_to_absolute(script_src: &Path) -> Result<PathBuf> {
     Ok(script_src.canonicalize()?)
 }
 
+/// A directory under the user's scriptisto cache directory, e.g. the cache root.
+pub fn cache_tree(name: &str) -> Result<PathBuf> {
+    let mut user_cache =
+        dirs::cache_dir().ok_or_else(|| anyhow!("Cannot compute user's cache dir"))?;
+    user_cache.push("scriptisto");
+    user_cache.push(name);
+    Ok(user_cache)
+}
+
+/// The directory holding build caches of all scripts.
+pub fn cache_root() -> Result<PathBuf> {
+    cache_tree("bin")
+}
+
 pub fn build_cache_path(script_path: &Path) -> Result<PathBuf> {
     let script_path = script_src_to_absolute(script_path)?;
     let script_path_rel = script_path
         .strip_prefix("/")
         .context(format!("Could not strip '/' prefix from {:?}", script_path))?;
 
-    let mut user_cache =
-        dirs::cache_dir().ok_or_else(|| anyhow!("Cannot compute user's cache dir"))?;
-    user_cache.push("scriptisto/bin");
+    let mut user_cache = cache_root()?;
     user_cache.push(script_path_rel);
     Ok(user_cache)
 }
 
-pub fn write_bytes(cache_path: &Path, rel_path: &Path, data: &[u8]) -> Result<()> {
-    let mut path = cache_path.to_path_buf();
-    path.push(rel_path);
-    debug!("Writing {} bytes to {:?}", data.len(), path);
+/// The path of a cache directory in another tree next to the cache root, e.g. for staging a build.
+/// Keeping these apart avoids collisions with the cache directories of other scripts.
+pub fn cache_state_path(cache_path: &Path, tree: &str) -> Result<PathBuf> {
+    let rel_path = cache_path.strip_prefix(cache_root()?).context(format!(
+        "Cache path {:?} is not in the cache directory",
+        cache_path
+    ))?;
+    Ok(cache_tree(tree)?.join(rel_path))
+}
+
+/// Removes the directories above `path` left empty, up to `root`.
+pub fn remove_empty_parents(path: &Path, root: &Path) {
+    for dir in path.ancestors().skip(1) {
+        if !dir.starts_with(root) || dir == root || std::fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+}
+
+/// Atomically swaps two existing paths, so neither of them is missing at any time.
+pub fn exchange_paths(a: &Path, b: &Path) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    let a = std::ffi::CString::new(a.as_os_str().as_bytes())?;
+    let b = std::ffi::CString::new(b.as_os_str().as_bytes())?;
+    rename_exchange(&a, &b)
+}
+
+// The syscall is used directly, as older libc versions have no renameat2() wrapper.
+#[cfg(target_os = "linux")]
+fn rename_exchange(a: &std::ffi::CStr, b: &std::ffi::CStr) -> std::io::Result<()> {
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_renameat2,
+            libc::AT_FDCWD,
+            a.as_ptr(),
+            libc::AT_FDCWD,
+            b.as_ptr(),
+            libc::RENAME_EXCHANGE,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn rename_exchange(a: &std::ffi::CStr, b: &std::ffi::CStr) -> std::io::Result<()> {
+    if unsafe { libc::renamex_np(a.as_ptr(), b.as_ptr(), libc::RENAME_SWAP) } == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn rename_exchange(_a: &std::ffi::CStr, _b: &std::ffi::CStr) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Normalizes a path relative to the cache directory, e.g. "./a/../b" to "b". Absolute paths and
+/// paths escaping the directory with ".." are rejected.
+pub fn normalize_relative_path(path: &Path) -> Result<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::Normal(name) => normalized.push(name),
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    return Err(anyhow!(
+                        "Path {:?} points outside of the cache directory",
+                        path
+                    ));
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => {
+                return Err(anyhow!(
+                    "Path {:?} must be relative to the cache directory",
+                    path
+                ));
+            }
+        }
+    }
+    if normalized.as_os_str().is_empty() {
+        return Err(anyhow!("Path {:?} does not point to a file", path));
+    }
+    Ok(normalized)
+}
+
+/// Recursively copies a directory, preserving symlinks and permissions.
+pub fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    for entry in walkdir::WalkDir::new(from) {
+        let entry = entry.context(format!("Cannot read directory {:?}", from))?;
+        let target = to.join(entry.path().strip_prefix(from)?);
+        let file_type = entry.file_type();
+        if file_type.is_dir() {
+            std::fs::create_dir_all(&target)
+                .context(format!("Cannot create directory {:?}", target))?;
+        } else if file_type.is_symlink() {
+            let link = std::fs::read_link(entry.path())?;
+            let _ = std::fs::remove_file(&target);
+            std::os::unix::fs::symlink(&link, &target)
+                .context(format!("Cannot create symlink {:?}", target))?;
+        } else {
+            // The existing file could be read-only.
+            if target.symlink_metadata().is_ok() {
+                std::fs::remove_file(&target)
+                    .context(format!("Cannot remove file {:?}", target))?;
+            }
+            std::fs::copy(entry.path(), &target).context(format!(
+                "Cannot copy {:?} to {:?}",
+                entry.path(),
+                target
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+// Gives the owner of a directory the permissions to change its entries.
+fn make_dir_writable(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = std::fs::metadata(path)
+        .context(format!("Cannot read permissions of {:?}", path))?
+        .permissions();
+    if permissions.mode() & 0o700 != 0o700 {
+        permissions.set_mode(permissions.mode() | 0o700);
+        std::fs::set_permissions(path, permissions)
+            .context(format!("Cannot make directory {:?} writable", path))?;
+    }
+    Ok(())
+}
+
+/// Removes a directory tree from the cache, also when a `mode` from the build spec made parts of it
+/// read-only.
+pub fn remove_cache_dir(path: &Path) -> std::io::Result<()> {
+    // Directories are yielded before their entries are read, so they can be fixed up first.
+    for entry in walkdir::WalkDir::new(path).into_iter().flatten() {
+        if entry.file_type().is_dir() {
+            let _ = make_dir_writable(entry.path());
+        }
+    }
+    std::fs::remove_dir_all(path)
+}
+
+/// Resolves a relative path from the build spec inside the cache directory and creates its
+/// parent directories. Paths escaping the cache directory, also through symlinks, are rejected.
+/// An existing file at the path is removed, and directories are made writable, so they can be
+/// rewritten whatever `mode` they were given by the previous build.
+pub fn cache_file_path(cache_path: &Path, rel_path: &Path) -> Result<PathBuf> {
+    let path = cache_path.join(normalize_relative_path(rel_path)?);
     let parent = path
         .parent()
         .ok_or_else(|| anyhow!("Cannot compute parent path of {:?}", path))?;
@@ -54,20 +217,58 @@ pub fn write_bytes(cache_path: &Path, rel_path: &Path, data: &[u8]) -> Result<()
         "Cannot create cache directory for script, dir path: {:?}",
         parent
     ))?;
+    let canonical_cache_path = cache_path.canonicalize()?;
+    if !parent.canonicalize()?.starts_with(&canonical_cache_path) {
+        return Err(anyhow!(
+            "Path {:?} points outside of the cache directory through a symlink",
+            rel_path
+        ));
+    }
+    if parent != cache_path {
+        make_dir_writable(parent)?;
+    }
+    // Symlinks are replaced too, instead of writing to wherever they point.
+    if let Ok(metadata) = path.symlink_metadata() {
+        if metadata.is_dir() {
+            make_dir_writable(&path)?;
+        } else {
+            std::fs::remove_file(&path).context(format!("Cannot remove file {:?}", path))?;
+        }
+    }
+    Ok(path)
+}
+
+pub fn write_bytes(cache_path: &Path, rel_path: &Path, data: &[u8]) -> Result<()> {
+    let path = cache_file_path(cache_path, rel_path)?;
+    debug!("Writing {} bytes to {:?}", data.len(), path);
     let mut file = File::create(path).context("Cannot output extra file")?;
     file.write_all(data).context("Cannot write bytes to file")?;
     Ok(())
 }
 
-pub fn file_modified(p: &Path) -> Result<std::time::SystemTime, std::io::Error> {
-    let meta = std::fs::metadata(p)?;
-    let modified = meta.modified()?;
-    Ok(modified)
+/// Sets the permission bits of the file, `executable` adds the execute bits for everyone that
+/// can read it.
+pub fn set_file_mode(path: &Path, mode: Option<u32>, executable: bool) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = std::fs::metadata(path)
+        .context(format!("Cannot read permissions of {:?}", path))?
+        .permissions();
+    if let Some(mode) = mode {
+        permissions.set_mode(mode);
+    }
+    if executable {
+        let mode = permissions.mode();
+        permissions.set_mode(mode | ((mode & 0o444) >> 2));
+    }
+    debug!("Setting mode of {:?} to {:o}", path, permissions.mode());
+    std::fs::set_permissions(path, permissions)
+        .context(format!("Cannot set permissions of {:?}", path))
 }
 
-pub fn run_command(
+/// Runs the command and returns its output, even if it failed.
+pub fn run_command_unchecked(
     current_directory: &Path,
-    mut cmd: Command,
+    cmd: &mut Command,
     stderr_mode: Stdio,
 ) -> Result<std::process::Output> {
     cmd.stdout(Stdio::piped())
@@ -81,18 +282,24 @@ pub fn run_command(
         cmd, current_directory
     ))?;
 
-    let stderr = String::from_utf8_lossy(&out.stderr);
-    let stdout = String::from_utf8_lossy(&out.stdout);
     debug!(
         "Command result: {:?}\nstderr:\n{}\nstdout:\n{}",
         out.status.code(),
-        stderr,
-        stdout
+        String::from_utf8_lossy(&out.stderr),
+        String::from_utf8_lossy(&out.stdout)
     );
 
+    Ok(out)
+}
+
+/// Prints the output of a failed command and turns it into an error.
+pub fn check_command_output(
+    cmd: &Command,
+    out: std::process::Output,
+) -> Result<std::process::Output> {
     if !out.status.success() {
-        eprintln!("{}", stderr);
-        eprintln!("{}", stdout);
+        eprintln!("{}", String::from_utf8_lossy(&out.stderr));
+        eprintln!("{}", String::from_utf8_lossy(&out.stdout));
         let error = match out.status.code() {
             Some(code) => anyhow!("Command {:?} failed. Exit code: {}.", cmd, code,),
             None => anyhow!("Child build process terminated by signal"),
@@ -102,3 +309,163 @@ pub fn run_command(
 
     Ok(out)
 }
+
+pub fn run_command(
+    current_directory: &Path,
+    mut cmd: Command,
+    stderr_mode: Stdio,
+) -> Result<std::process::Output> {
+    let out = run_command_unchecked(current_directory, &mut cmd, stderr_mode)?;
+    check_command_output(&cmd, out)
+}
+
+pub fn print_ascii_table(titles: &[&str], rows: &[Vec<String>]) {
+    use prettytable::{format, Cell, Row, Table};
+
+    let mut table = Table::new();
+    let format = format::FormatBuilder::new()
+        .column_separator('|')
+        .borders('|')
+        .separators(
+            &[format::LinePosition::Top, format::LinePosition::Bottom],
+            format::LineSeparator::new('-', '+', '+', '+'),
+        )
+        .separators(
+            &[format::LinePosition::Title],
+            format::LineSeparator::new('=', '+', '+', '+'),
+        )
+        .padding(1, 1)
+        .build();
+    table.set_format(format);
+    table.set_titles(Row::new(titles.iter().map(|s| Cell::new(s)).collect()));
+    for table_row in rows {
+        table.add_row(Row::new(table_row.iter().map(|s| Cell::new(s)).collect()));
+    }
+    table.printstd();
+}
+
+/// Prints a value in one of the machine-readable formats.
+pub fn print_serialized<T: serde::Serialize>(format: OutputFormat, value: &T) -> Result<()> {
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(value)?),
+        OutputFormat::Yaml => print!("{}", serde_yaml::to_string(value)?),
+        OutputFormat::Table => {
+            return Err(anyhow!(
+                "PROBABLY A BUG: table output is not a serialization format."
+            ))
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_relative_path_accepts_paths_inside() {
+        let normalize = |p: &str| normalize_relative_path(Path::new(p)).unwrap();
+        assert_eq!(normalize("script"), PathBuf::from("script"));
+        assert_eq!(
+            normalize("./target/release/script"),
+            PathBuf::from("target/release/script")
+        );
+        assert_eq!(normalize("src/../Cargo.toml"), PathBuf::from("Cargo.toml"));
+        assert_eq!(normalize("a/./b/../c"), PathBuf::from("a/c"));
+    }
+
+    #[test]
+    fn normalize_relative_path_rejects_paths_outside() {
+        for path in [
+            "../script",
+            "../../../.bashrc",
+            "src/../../script",
+            "/etc/passwd",
+            "/",
+            ".",
+            "a/..",
+            "",
+        ] {
+            assert!(
+                normalize_relative_path(Path::new(path)).is_err(),
+                "{:?} should be rejected",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn write_bytes_writes_inside_cache_dir() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        write_bytes(dir, Path::new("./a/../b/c.txt"), b"data").unwrap();
+        assert_eq!(std::fs::read(dir.join("b/c.txt")).unwrap(), b"data");
+    }
+
+    #[test]
+    fn write_bytes_rejects_traversal() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let cache_dir = dir.join("cache");
+        std::fs::create_dir_all(&cache_dir).unwrap();
+
+        assert!(write_bytes(&cache_dir, Path::new("../evil"), b"data").is_err());
+        assert!(write_bytes(&cache_dir, Path::new("sub/../../evil"), b"data").is_err());
+        let absolute = dir.join("evil");
+        assert!(write_bytes(&cache_dir, &absolute, b"data").is_err());
+        assert!(!dir.join("evil").exists());
+    }
+
+    #[test]
+    fn write_bytes_rejects_symlink_escape() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let cache_dir = dir.join("cache");
+        let outside = dir.join("outside");
+        std::fs::create_dir_all(&cache_dir).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, cache_dir.join("link")).unwrap();
+
+        assert!(write_bytes(&cache_dir, Path::new("link/evil"), b"data").is_err());
+        assert!(!outside.join("evil").exists());
+    }
+
+    #[test]
+    fn write_bytes_replaces_symlinked_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let cache_dir = dir.join("cache");
+        let outside = dir.join("outside.txt");
+        std::fs::create_dir_all(&cache_dir).unwrap();
+        std::fs::write(&outside, b"original").unwrap();
+        std::os::unix::fs::symlink(&outside, cache_dir.join("file.txt")).unwrap();
+
+        write_bytes(&cache_dir, Path::new("file.txt"), b"data").unwrap();
+        assert_eq!(std::fs::read(&outside).unwrap(), b"original");
+        assert_eq!(std::fs::read(cache_dir.join("file.txt")).unwrap(), b"data");
+    }
+
+    #[test]
+    fn write_bytes_replaces_read_only_file() {
+        use std::os::unix::fs::PermissionsExt;
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join("a/file.txt");
+
+        for data in [b"first", b"again"] {
+            write_bytes(dir, Path::new("a/file.txt"), data).unwrap();
+            // Running as root would ignore the mode, so check that the file was replaced.
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_ne!(mode & 0o200, 0);
+            let dir_mode = std::fs::metadata(dir.join("a"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(dir_mode & 0o700, 0o700);
+            set_file_mode(&path, Some(0o444), false).unwrap();
+            set_file_mode(&dir.join("a"), Some(0o555), false).unwrap();
+        }
+        assert_eq!(std::fs::read(&path).unwrap(), b"again");
+        set_file_mode(&dir.join("a"), Some(0o755), false).unwrap();
+    }
+}
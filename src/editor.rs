@@ -1,13 +1,7 @@
-use std::{
-    env, fs,
-    path::{Path, PathBuf},
-    process::Command,
-};
+use std::{env, fs, path::Path, process::Command};
 
 use anyhow::Context;
 
-use crate::common;
-
 pub fn edit<P: AsRef<Path>>(source_file_path: P, content: &str) -> anyhow::Result<Option<String>> {
     use std::env::var;
 
@@ -28,7 +22,7 @@ pub fn edit<P: AsRef<Path>>(source_file_path: P, content: &str) -> anyhow::Resul
     };
     log::info!("{:?}", tmp_file);
 
-    common::write_bytes(&PathBuf::from("/"), &tmp_file, content.as_bytes())?;
+    fs::write(&tmp_file, content).with_context(|| format!("Unable to write {:?}", tmp_file))?;
 
     let editors = [
         var("VISUAL"),
@@ -19,16 +19,23 @@ use anyhow::{anyhow, Context, Result};
 use clap::Parser;
 use log::debug;
 use std::env;
+use std::io::IsTerminal;
 use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::str::FromStr;
 
 mod build;
+mod buildlog;
 mod cache;
 mod cfg;
+mod check;
 mod common;
 mod editor;
+mod metadata;
 mod opt;
+mod progress;
+mod schema;
+mod sources;
 mod templates;
 
 pub fn opt_from_args(args: &[String]) -> opt::Opt {
@@ -58,10 +65,19 @@ pub fn opt_from_args(args: &[String]) -> opt::Opt {
 fn default_main(script_path: &str, args: &[String]) -> Result<()> {
     let build_mode_env = std::env::var_os("SCRIPTISTO_BUILD").unwrap_or_default();
     let build_mode = opt::BuildMode::from_str(&build_mode_env.to_string_lossy())?;
-    let show_logs = std::env::var_os("SCRIPTISTO_BUILD_LOGS").is_some();
+    let output = if std::env::var_os("SCRIPTISTO_BUILD_LOGS").is_some() {
+        build::BuildOutput::Verbose
+    } else if std::io::stderr().is_terminal() {
+        build::BuildOutput::Progress
+    } else {
+        build::BuildOutput::Quiet
+    };
+    let allow_fallback = std::env::var_os(build::SCRIPTISTO_BUILD_FALLBACK_VAR).is_some();
 
-    let (cfg, script_cache_path) = build::perform(build_mode, script_path, show_logs)
-        .context(format!("Build failed for {:?}", script_path))?;
+    // The cache lock is held until the script is executed, so the cache is not removed meanwhile.
+    let (cfg, script_cache_path, _lock) =
+        build::perform(build_mode, script_path, output, allow_fallback)
+            .context(format!("Build failed for {:?}", script_path))?;
 
     let mut full_target_bin = script_cache_path.clone();
     full_target_bin.push(PathBuf::from(cfg.target_bin));
@@ -92,6 +108,8 @@ fn default_main(script_path: &str, args: &[String]) -> Result<()> {
     target_argv.extend_from_slice(args);
     debug!("Running exec {:?}, Args: {:?}", binary, target_argv);
 
+    metadata::touch_last_used(&script_cache_path);
+
     // Scripts can use this to find other build artifacts
     env::set_var(build::SCRIPTISTO_CACHE_DIR_VAR, script_cache_path);
 
@@ -126,9 +144,15 @@ fn main_err() -> Result<()> {
             script_src,
             build_mode,
         }) => {
-            let _ = build::perform(build_mode.unwrap_or_default(), &script_src, true);
+            let _ = build::perform(
+                build_mode.unwrap_or_default(),
+                &script_src,
+                build::BuildOutput::Verbose,
+                false,
+            );
             Ok(())
         }
+        Some(opt::Command::Check { files, templates }) => check::command_check(files, templates),
     }
 }
 
@@ -0,0 +1,117 @@
+// Copyright 2019 The Scriptisto Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use anyhow::{Context, Result};
+use log::debug;
+use serde_derive::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::common;
+
+pub const METADATA_FILE_NAME: &str = "scriptisto.metadata";
+pub const LAST_USED_FILE_NAME: &str = "scriptisto.last_used";
+
+/// A record of the last build, stored as YAML in the cache directory.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Metadata {
+    pub scriptisto_version: String,
+    /// Seconds since the Unix epoch.
+    pub build_time: u64,
+    /// Digest of the build inputs. Only set if the build succeeded.
+    pub input_digest: Option<String>,
+    /// Digest of the last `build_once_cmd` that completed successfully.
+    #[serde(default)]
+    pub build_once_digest: Option<String>,
+    #[serde(default)]
+    pub steps: Vec<Step>,
+    /// Set when the Docker source volume is created, even if the build fails afterwards.
+    #[serde(default)]
+    pub docker_build: bool,
+    pub docker_image_id: Option<String>,
+    /// Absolute path to the target binary.
+    pub target_bin: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Step {
+    pub name: String,
+    pub command: String,
+    /// None if the command was not started or was killed by a signal.
+    pub exit_code: Option<i32>,
+    pub duration_secs: f64,
+}
+
+impl Metadata {
+    pub fn new(target_bin: &Path) -> Self {
+        Metadata {
+            scriptisto_version: env!("CARGO_PKG_VERSION").into(),
+            build_time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            target_bin: target_bin.to_string_lossy().to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn build_time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.build_time)
+    }
+
+    fn path(cache_path: &Path) -> PathBuf {
+        cache_path.join(METADATA_FILE_NAME)
+    }
+
+    /// Reads the metadata of the last build. Returns None if there was no build or the file is
+    /// unreadable, e.g. written by an older version.
+    pub fn read(cache_path: &Path) -> Option<Self> {
+        let path = Self::path(cache_path);
+        let data = std::fs::read(&path).ok()?;
+        match serde_yaml::from_slice(&data) {
+            Ok(metadata) => Some(metadata),
+            Err(e) => {
+                debug!("Cannot parse metadata file {:?}: {:?}", path, e);
+                None
+            }
+        }
+    }
+
+    pub fn write(&self, cache_path: &Path) -> Result<()> {
+        let data = serde_yaml::to_string(self).context("Cannot serialize metadata")?;
+        common::write_bytes(cache_path, Path::new(METADATA_FILE_NAME), data.as_bytes())
+            .context("Cannot write metadata file")
+    }
+}
+
+/// Marks the cache as used now. Only the modification time of an empty file is updated, which is
+/// cheap enough to do on every run.
+pub fn touch_last_used(cache_path: &Path) {
+    let path = cache_path.join(LAST_USED_FILE_NAME);
+    let result = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .and_then(|file| file.set_modified(SystemTime::now()));
+    if let Err(e) = result {
+        debug!("Cannot update {:?}: {:?}", path, e);
+    }
+}
+
+pub fn last_used(cache_path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(cache_path.join(LAST_USED_FILE_NAME))
+        .and_then(|m| m.modified())
+        .ok()
+}
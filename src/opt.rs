@@ -22,6 +22,9 @@ pub enum CacheCommand {
     Info {
         #[clap(help = "A filename of the script file.")]
         file: PathBuf,
+        /// Output format: "table", "json" or "yaml".
+        #[clap(short, long)]
+        format: Option<OutputFormat>,
     },
     /// Clean the cache for a particular script. Removes the cache directory. Removes the Docker image/volume if
     /// they exist, but does not prune.
@@ -36,6 +39,37 @@ pub enum CacheCommand {
         name: String,
         #[clap(help = "A filename of the script file.")]
         file: PathBuf,
+        /// Output format: "table" for the raw value, "json" or "yaml".
+        #[clap(short, long)]
+        format: Option<OutputFormat>,
+    },
+    /// Prints the output of the last build of the script.
+    Log {
+        #[clap(help = "A filename of the script file.")]
+        file: PathBuf,
+        /// Print the log of an earlier build instead, 1 being the one before the last.
+        #[clap(short, long, default_value = "0")]
+        previous: usize,
+    },
+    /// Lists all cached scripts.
+    #[clap(name = "ls", visible_alias = "list")]
+    List {
+        /// Output format: "table", "json" or "yaml".
+        #[clap(short, long)]
+        format: Option<OutputFormat>,
+    },
+    /// Removes caches of scripts that no longer exist, and optionally caches not used recently or
+    /// exceeding the total size budget. Docker images/volumes are removed as in "clean".
+    Gc {
+        /// Remove caches not used for this long, e.g. "30days" or "12h".
+        #[clap(long)]
+        max_age: Option<humantime::Duration>,
+        /// Remove least recently used caches until the total size fits, e.g. "10G" or "500M".
+        #[clap(long)]
+        max_size: Option<ByteSize>,
+        /// Only print what would be removed.
+        #[clap(short = 'n', long)]
+        dry_run: bool,
     },
 }
 
@@ -61,7 +95,11 @@ pub enum TemplatesCommand {
     },
     /// List all templates.
     #[clap(name = "ls", visible_alias = "list")]
-    List {},
+    List {
+        /// Output format: "table", "json" or "yaml".
+        #[clap(short, long)]
+        format: Option<OutputFormat>,
+    },
 }
 
 #[derive(Debug, PartialEq, Eq, Parser, Clone, Default)]
@@ -91,11 +129,78 @@ impl FromStr for BuildMode {
     }
 }
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum OutputFormat {
+    #[default]
+    Table,
+    Json,
+    Yaml,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        use OutputFormat::*;
+        Ok(match s {
+            "table" => Table,
+            "json" => Json,
+            "yaml" => Yaml,
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "Incorrect output format. Available values: table, json, yaml."
+                ))
+            }
+        })
+    }
+}
+
+/// A number of bytes with an optional binary suffix, e.g. "512M" or "10GiB".
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let s = s.trim();
+        let digits_end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (number, suffix) = s.split_at(digits_end);
+        let number: u64 = number
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Incorrect size: {:?}", s))?;
+        let shift = match suffix.trim().trim_end_matches("iB").trim_end_matches('B') {
+            "" => 0,
+            "K" | "k" => 10,
+            "M" | "m" => 20,
+            "G" | "g" => 30,
+            "T" | "t" => 40,
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "Incorrect size suffix in {:?}. Available suffixes: K, M, G, T.",
+                    s
+                ))
+            }
+        };
+        Ok(ByteSize(number << shift))
+    }
+}
+
+const ENVIRONMENT_HELP: &str = "ENVIRONMENT:
+    SCRIPTISTO_BUILD             Build mode when running a script: \"source\" or \"full\", see \"build\".
+    SCRIPTISTO_BUILD_LOGS        If set, passes the build output through to stderr.
+    SCRIPTISTO_BUILD_TIMEOUT     Timeout of builds without build_timeout, e.g. \"10m\".
+    SCRIPTISTO_BUILD_FALLBACK    If set, runs the previous build when a build fails. Requires
+                                 staged_build, which is on unless the build spec disables it.
+    SCRIPTISTO_CACHE_MAX_SIZE    If set, evicts least recently used caches after each build to fit
+                                 into this size, e.g. \"10G\".";
+
 #[derive(Debug, Parser, PartialEq, Eq)]
 #[clap(
     name = "scriptisto",
     about = "A 'shebang-interpreter' for compiled languages",
-    args_conflicts_with_subcommands = true
+    args_conflicts_with_subcommands = true,
+    after_help = ENVIRONMENT_HELP
 )]
 pub struct Opt {
     /// A path for to a script to run and additional arguments passed to this script. A script path must start with '.' or '/'.
@@ -135,8 +240,41 @@ pub enum Command {
         #[clap(short, long)]
         build_mode: Option<BuildMode>,
     },
+    /// Checks scripts for errors in the build spec without building them.
+    Check {
+        /// Scripts to check.
+        #[clap(required_unless_present = "templates")]
+        files: Vec<PathBuf>,
+        /// Also check all built-in and custom templates.
+        #[clap(short, long)]
+        templates: bool,
+    },
 }
 
 pub fn display_help() {
     Opt::parse_from(vec!["", "help"]);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_size_reads_suffixes() {
+        let size = |s: &str| ByteSize::from_str(s).unwrap().0;
+        assert_eq!(size("512"), 512);
+        assert_eq!(size("2K"), 2 << 10);
+        assert_eq!(size("512M"), 512 << 20);
+        assert_eq!(size("10G"), 10 << 30);
+        assert_eq!(size("10GiB"), 10 << 30);
+        assert_eq!(size("3 TB"), 3 << 40);
+        assert_eq!(size(" 1k "), 1 << 10);
+    }
+
+    #[test]
+    fn byte_size_rejects_invalid_sizes() {
+        for s in ["", "G", "10X", "1.5G", "-1M"] {
+            assert!(ByteSize::from_str(s).is_err(), "{:?} should be rejected", s);
+        }
+    }
+}
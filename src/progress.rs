@@ -0,0 +1,312 @@
+// Copyright 2019 The Scriptisto Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use anyhow::{Context, Result};
+use log::debug;
+use std::fmt;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{mpsc, Once};
+use std::time::{Duration, Instant};
+
+use crate::build::BuildOutput;
+use crate::buildlog::BuildLog;
+
+const SPINNER: &[char] = &['|', '/', '-', '\\'];
+const TICK: Duration = Duration::from_millis(100);
+// Time given to the build processes to exit after SIGTERM, before they are killed.
+const KILL_GRACE_PERIOD: Duration = Duration::from_secs(3);
+
+/// Why a build was stopped before it completed.
+#[derive(Debug)]
+pub enum Aborted {
+    Timeout(Duration),
+    Signal(i32),
+}
+
+impl fmt::Display for Aborted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Aborted::Timeout(timeout) => write!(
+                f,
+                "Build timed out after {}, see build_timeout and SCRIPTISTO_BUILD_TIMEOUT",
+                humantime::format_duration(*timeout)
+            ),
+            Aborted::Signal(libc::SIGINT) => write!(f, "Build interrupted"),
+            Aborted::Signal(signal) => write!(f, "Build stopped by signal {}", signal),
+        }
+    }
+}
+
+impl std::error::Error for Aborted {}
+
+static RECEIVED_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    RECEIVED_SIGNAL.store(signal, Ordering::SeqCst);
+}
+
+// Build commands run in their own process group, so a Ctrl-C on the terminal only reaches
+// scriptisto, which then stops the whole group. The handlers are reset when the script is executed.
+fn install_signal_handlers() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let handler: extern "C" fn(libc::c_int) = on_signal;
+        for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+            unsafe { libc::signal(signal, handler as libc::sighandler_t) };
+        }
+    });
+}
+
+/// Fails if a signal asked scriptisto to stop the build.
+pub fn check_interrupted() -> Result<()> {
+    match RECEIVED_SIGNAL.load(Ordering::SeqCst) {
+        0 => Ok(()),
+        signal => Err(Aborted::Signal(signal).into()),
+    }
+}
+
+/// Deadline of the build, and the timeout it was computed from.
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline {
+    pub at: Instant,
+    pub timeout: Duration,
+}
+
+impl Deadline {
+    pub fn check(&self) -> Result<()> {
+        if Instant::now() >= self.at {
+            return Err(Aborted::Timeout(self.timeout).into());
+        }
+        Ok(())
+    }
+}
+
+// Asks the process group of the child to terminate, then kills whatever is left of it.
+fn kill_process_group(child: &mut Child) {
+    let pgid = child.id() as libc::pid_t;
+    debug!("Stopping process group {}", pgid);
+    unsafe { libc::killpg(pgid, libc::SIGTERM) };
+    let start = Instant::now();
+    while start.elapsed() < KILL_GRACE_PERIOD {
+        if let Ok(Some(_)) = child.try_wait() {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    unsafe { libc::killpg(pgid, libc::SIGKILL) };
+}
+
+fn terminal_width() -> usize {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    let ok = unsafe { libc::ioctl(libc::STDERR_FILENO, libc::TIOCGWINSZ, &mut size) } == 0;
+    if ok && size.ws_col > 0 {
+        size.ws_col as usize
+    } else {
+        80
+    }
+}
+
+/// A single status line on stderr: a spinner, the step name, elapsed time and the last line of
+/// the build output.
+struct StatusLine<'a> {
+    step: &'a str,
+    start: Instant,
+    frame: usize,
+    last_line: String,
+    drawn_at: Option<Instant>,
+}
+
+impl StatusLine<'_> {
+    // Redraws at most once per tick, however fast the output comes.
+    fn draw(&mut self) {
+        if self.drawn_at.map(|t| t.elapsed() < TICK).unwrap_or(false) {
+            return;
+        }
+        self.drawn_at = Some(Instant::now());
+        self.frame = (self.frame + 1) % SPINNER.len();
+        let status = format!(
+            "{} {} ({}s) ",
+            SPINNER[self.frame],
+            self.step,
+            self.start.elapsed().as_secs()
+        );
+        let width = terminal_width().saturating_sub(status.len() + 1);
+        let last_line: String = self.last_line.chars().take(width).collect();
+        eprint!("\r\x1b[2K{}{}", status, last_line);
+        let _ = std::io::stderr().flush();
+    }
+
+    fn clear(&self) {
+        eprint!("\r\x1b[2K");
+        let _ = std::io::stderr().flush();
+    }
+}
+
+// Sends lines read from a pipe to the channel.
+fn forward_lines<R: Read + Send + 'static>(
+    reader: R,
+    tx: mpsc::Sender<Vec<u8>>,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut reader = BufReader::new(reader);
+        loop {
+            let mut line = vec![];
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    if tx.send(line).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Output of a build step. `lines` has stdout and stderr lines in the order they were written.
+pub struct StepOutput {
+    pub status: ExitStatus,
+    pub lines: Vec<Vec<u8>>,
+}
+
+/// Runs a build step command, capturing its output and copying it to the build log as it comes.
+/// Depending on `output`, shows a status line on the terminal or passes the output through.
+/// The command and all its children are stopped on a signal or when the deadline passes.
+pub fn run_step_command(
+    current_directory: &Path,
+    cmd: &mut Command,
+    step: &str,
+    output: BuildOutput,
+    log: &mut BuildLog,
+    deadline: Option<Deadline>,
+) -> Result<StepOutput> {
+    install_signal_handlers();
+    check_interrupted()?;
+    if let Some(deadline) = deadline {
+        deadline.check()?;
+    }
+    // stdout and stderr share one pipe, so the kernel keeps the order the output was written in.
+    let (reader, writer) = std::io::pipe().context("Cannot create a pipe for the build output")?;
+    let stderr_writer = writer
+        .try_clone()
+        .context("Cannot create a pipe for the build output")?;
+    cmd.stdin(Stdio::null())
+        .stdout(writer)
+        .stderr(stderr_writer)
+        .current_dir(current_directory)
+        .process_group(0);
+
+    debug!("Running command: {:?}", cmd);
+
+    let spawned = cmd.spawn();
+    // The command holds the write ends until they are replaced, the reader would not see EOF.
+    cmd.stdout(Stdio::null()).stderr(Stdio::null());
+    let mut child = spawned.context(format!(
+        "Cannot run cmd={:?} in current_directory={:?}",
+        cmd, current_directory
+    ))?;
+
+    let (tx, rx) = mpsc::channel();
+    let reader = forward_lines(reader, tx);
+
+    let mut status_line = match output {
+        BuildOutput::Progress => Some(StatusLine {
+            step,
+            start: Instant::now(),
+            frame: 0,
+            last_line: String::new(),
+            drawn_at: None,
+        }),
+        _ => None,
+    };
+    let mut lines = vec![];
+    let mut aborted = None;
+    loop {
+        if let Err(e) = check_interrupted().and_then(|()| match deadline {
+            Some(deadline) => deadline.check(),
+            None => Ok(()),
+        }) {
+            kill_process_group(&mut child);
+            aborted = Some(e);
+            break;
+        }
+        if let Some(status_line) = status_line.as_mut() {
+            status_line.draw();
+        }
+        match rx.recv_timeout(TICK) {
+            Ok(line) => {
+                log.write(&line);
+                if let Some(status_line) = status_line.as_mut() {
+                    status_line.last_line = String::from_utf8_lossy(&line).trim().to_string();
+                }
+                if output == BuildOutput::Verbose {
+                    let _ = std::io::stderr().write_all(&line);
+                }
+                lines.push(line);
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    if let Some(status_line) = status_line {
+        status_line.clear();
+    }
+
+    if let Some(e) = aborted {
+        // The reader is not joined, as a process that left the group could keep the pipe open.
+        let _ = child.wait();
+        return Err(e);
+    }
+
+    let _ = reader.join();
+    let status = child.wait().context("Cannot wait for the build command")?;
+
+    debug!(
+        "Command result: {:?}\noutput:\n{}",
+        status.code(),
+        String::from_utf8_lossy(&lines.concat())
+    );
+
+    Ok(StepOutput { status, lines })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_step_command_keeps_output_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = BuildLog::create(dir.path(), Path::new("./script.sh")).unwrap();
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c")
+            .arg("echo out1; echo err1 >&2; echo out2; echo err2 >&2; exit 3");
+
+        let out = run_step_command(
+            dir.path(),
+            &mut cmd,
+            "build_cmd",
+            BuildOutput::Quiet,
+            &mut log,
+            None,
+        )
+        .unwrap();
+        assert_eq!(out.status.code(), Some(3));
+        assert_eq!(out.lines.concat(), b"out1\nerr1\nout2\nerr2\n");
+    }
+}
@@ -0,0 +1,315 @@
+// Copyright 2019 The Scriptisto Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use anyhow::Result;
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use serde_yaml::Value;
+use std::fmt;
+
+use crate::cfg;
+
+// Serde derive passes the names of all fields to `deserialize_struct`, this deserializer only
+// records them and fails.
+struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+impl<'de> Deserializer<'de> for FieldNames<'_> {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+        Err(de::Error::custom("not a struct"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        *self.0 = fields;
+        Err(de::Error::custom("only field names are needed"))
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        option unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier
+        ignored_any
+    }
+}
+
+/// An error in the build spec, with the script line it was found at, if known.
+#[derive(Debug)]
+pub struct SpecError {
+    pub line: Option<usize>,
+    // The line number goes between these two parts of the message.
+    subject: String,
+    detail: String,
+}
+
+impl SpecError {
+    pub fn new(line: Option<usize>, subject: String, detail: String) -> Self {
+        SpecError {
+            line,
+            subject,
+            detail,
+        }
+    }
+
+    /// The message without the line number, for output showing it separately.
+    pub fn message(&self) -> String {
+        format!("{}{}", self.subject, self.detail)
+    }
+}
+
+impl fmt::Display for SpecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{} at line {}{}", self.subject, line, self.detail),
+            None => write!(f, "{}{}", self.subject, self.detail),
+        }
+    }
+}
+
+/// All errors found in the build spec, one per line when displayed.
+#[derive(Debug)]
+pub struct SpecErrors(pub Vec<SpecError>);
+
+impl fmt::Display for SpecErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let messages: Vec<String> = self.0.iter().map(|e| e.to_string()).collect();
+        write!(f, "{}", messages.join("\n"))
+    }
+}
+
+impl std::error::Error for SpecErrors {}
+
+fn field_names<'de, T: Deserialize<'de>>() -> &'static [&'static str] {
+    let mut fields: &'static [&'static str] = &[];
+    let _ = T::deserialize(FieldNames(&mut fields));
+    fields
+}
+
+fn suggest<'a>(key: &str, candidates: &[&'a str]) -> Option<&'a str> {
+    candidates
+        .iter()
+        .map(|c| (strsim::jaro_winkler(key, c), *c))
+        .filter(|(score, _)| *score > 0.8)
+        .max_by(|a, b| a.0.total_cmp(&b.0))
+        .map(|(_, c)| c)
+}
+
+/// Checks the keys of a parsed config block, so typos are reported instead of being ignored.
+/// `lines` is the config block and `first_line` the script line number of its first line, used
+/// to point at the offending key.
+pub fn validate_keys(cfg: &Value, lines: &[String], first_line: usize) -> Result<()> {
+    let mut unknown = vec![];
+    check_mapping(cfg, "", field_names::<cfg::BuildSpec>(), &mut unknown);
+
+    // Keys are visited in document order, so each one is searched after the line of the previous
+    // one.
+    let mut errors = vec![];
+    let mut search_from = 0;
+    for (path, key, fields) in unknown {
+        let mut line = None;
+        if let Some(offset) = lines[search_from..]
+            .iter()
+            .position(|line| is_key_line(line, &key))
+        {
+            line = Some(first_line + search_from + offset);
+            search_from += offset + 1;
+        }
+        let detail = match suggest(&key, fields) {
+            Some(suggestion) => format!(", did you mean '{}'?", suggestion),
+            None => format!(", expected one of: {}", fields.join(", ")),
+        };
+        errors.push(SpecError::new(
+            line,
+            format!("Unknown key '{}{}'", path, key),
+            detail,
+        ));
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(SpecErrors(errors).into())
+    }
+}
+
+// Collects unknown keys of the mapping and of the nested `files` and `docker_build` sections.
+fn check_mapping(
+    value: &Value,
+    path: &str,
+    fields: &'static [&'static str],
+    unknown: &mut Vec<(String, String, &'static [&'static str])>,
+) {
+    let mapping = match value {
+        Value::Mapping(mapping) => mapping,
+        _ => return,
+    };
+    for (key, value) in mapping {
+        let key = match key {
+            Value::String(key) => key.clone(),
+            other => serde_yaml::to_string(other)
+                .unwrap_or_default()
+                .trim()
+                .to_string(),
+        };
+        if !fields.contains(&key.as_str()) {
+            unknown.push((path.to_string(), key, fields));
+            continue;
+        }
+        match (path, key.as_str(), value) {
+            ("", "files", Value::Sequence(files)) => {
+                for (i, file) in files.iter().enumerate() {
+                    let path = format!("files[{}].", i);
+                    check_mapping(file, &path, field_names::<cfg::File>(), unknown);
+                }
+            }
+            ("", "docker_build", docker_build) => {
+                let fields = field_names::<cfg::DockerBuild>();
+                check_mapping(docker_build, "docker_build.", fields, unknown);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Returns the script line number of the first config line defining `key`, and containing
+/// `value` if given.
+pub fn find_key_line(
+    lines: &[String],
+    first_line: usize,
+    key: &str,
+    value: Option<&str>,
+) -> Option<usize> {
+    lines
+        .iter()
+        .position(|line| is_key_line(line, key) && value.map(|v| line.contains(v)).unwrap_or(true))
+        .map(|i| first_line + i)
+}
+
+// Whether the config line defines `key`, possibly as the first key of a list item.
+fn is_key_line(line: &str, key: &str) -> bool {
+    let line = line.trim_start().trim_start_matches("- ").trim_start();
+    line.strip_prefix(key)
+        .map(|rest| rest.trim_start().starts_with(':'))
+        .unwrap_or(false)
+}
+
+/// Rewrites "line N column M" positions in a YAML error message, which are relative to the config
+/// block, into script line numbers.
+pub fn remap_error_lines(message: &str, first_line: usize) -> String {
+    let mut result = String::with_capacity(message.len());
+    let mut rest = message;
+    while let Some(pos) = rest.find("line ") {
+        let (before, after) = rest.split_at(pos + "line ".len());
+        result.push_str(before);
+        let digits_len = after
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(after.len());
+        match after[..digits_len].parse::<usize>() {
+            Ok(line) if after[digits_len..].starts_with(" column") => {
+                result.push_str(&(first_line + line - 1).to_string());
+            }
+            _ => result.push_str(&after[..digits_len]),
+        }
+        rest = &after[digits_len..];
+    }
+    result.push_str(rest);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Validates the config block as if it started at the given script line.
+    fn errors(cfg: &str, first_line: usize) -> Vec<(Option<usize>, String)> {
+        let lines: Vec<String> = cfg.lines().map(String::from).collect();
+        let value: Value = serde_yaml::from_str(cfg).unwrap();
+        let err = validate_keys(&value, &lines, first_line).unwrap_err();
+        err.downcast::<SpecErrors>()
+            .unwrap()
+            .0
+            .iter()
+            .map(|e| (e.line, e.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn validate_keys_reports_top_level_typo() {
+        assert_eq!(
+            errors("script_src: main.sh\nbuild_cmdd: make", 3),
+            vec![(
+                Some(4),
+                "Unknown key 'build_cmdd' at line 4, did you mean 'build_cmd'?".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn validate_keys_reports_typos_in_files_in_order() {
+        let cfg = "script_src: main.sh\nfiles:\n - path: a\n   contnt: x\n - path: b\n   contnt: y";
+        assert_eq!(
+            errors(cfg, 3),
+            vec![
+                (
+                    Some(6),
+                    "Unknown key 'files[0].contnt' at line 6, did you mean 'content'?".to_string()
+                ),
+                (
+                    Some(8),
+                    "Unknown key 'files[1].contnt' at line 8, did you mean 'content'?".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn validate_keys_reports_typo_in_docker_build() {
+        let cfg = "script_src: main.sh\ndocker_build:\n  dockerfil: FROM alpine";
+        assert_eq!(
+            errors(cfg, 10),
+            vec![(
+                Some(12),
+                "Unknown key 'docker_build.dockerfil' at line 12, did you mean 'dockerfile'?"
+                    .to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn validate_keys_lists_fields_without_close_match() {
+        let errors = errors("script_src: main.sh\nxyz: 1", 1);
+        assert_eq!(errors[0].0, Some(2));
+        assert!(errors[0]
+            .1
+            .starts_with("Unknown key 'xyz' at line 2, expected one of: extends, script_src,"));
+    }
+
+    #[test]
+    fn remap_error_lines_rewrites_positions() {
+        assert_eq!(
+            remap_error_lines(
+                "did not find expected key at line 3 column 5, while parsing a block mapping at line 1 column 1",
+                10
+            ),
+            "did not find expected key at line 12 column 5, while parsing a block mapping at line 10 column 1"
+        );
+        assert_eq!(
+            remap_error_lines("value on line 3 is wrong", 10),
+            "value on line 3 is wrong"
+        );
+    }
+}
@@ -0,0 +1,428 @@
+// Copyright 2019 The Scriptisto Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use anyhow::{anyhow, Context, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use log::debug;
+use std::collections::BTreeSet;
+use std::path::{Component, Path, PathBuf};
+
+use crate::cfg;
+use crate::common;
+
+const MAX_FILES_PER_PATH: usize = 500000;
+
+fn is_glob(s: &str) -> bool {
+    s.contains(['*', '?', '[', '{'])
+}
+
+// Splits a glob into the directory to walk and the pattern for paths relative to it.
+fn split_glob(full_path: &Path) -> (PathBuf, String) {
+    let mut base = PathBuf::new();
+    let mut pattern = vec![];
+    for component in full_path.components() {
+        let s = component.as_os_str().to_string_lossy();
+        if pattern.is_empty() && !is_glob(&s) {
+            base.push(component);
+        } else if let Component::Normal(_) = component {
+            pattern.push(s.to_string());
+        }
+    }
+    (base, pattern.join("/"))
+}
+
+fn build_exclude_matcher(cfg: &cfg::BuildSpec, script_dir: &Path) -> Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new(script_dir);
+    for pattern in cfg.extra_src_exclude.iter() {
+        builder
+            .add_line(None, pattern)
+            .context(format!("Invalid extra_src_exclude pattern {:?}", pattern))?;
+    }
+    builder
+        .build()
+        .context("Cannot build extra_src_exclude matcher")
+}
+
+/// Lists every file matched by `extra_src_paths`, minus `extra_src_exclude`, in a stable order.
+/// Relative paths and patterns are resolved against the script directory. With
+/// `extra_src_gitignore`, files ignored by `.gitignore`/`.ignore` files are skipped as well.
+pub fn collect_extra_src_files(cfg: &cfg::BuildSpec, script_dir: &Path) -> Result<Vec<PathBuf>> {
+    collect_extra_src_files_with_limit(cfg, script_dir, MAX_FILES_PER_PATH)
+}
+
+// Fails if an entry of `extra_src_paths` has more than `max_files` files to scan.
+fn collect_extra_src_files_with_limit(
+    cfg: &cfg::BuildSpec,
+    script_dir: &Path,
+    max_files: usize,
+) -> Result<Vec<PathBuf>> {
+    let excludes = build_exclude_matcher(cfg, script_dir)?;
+    let mut files = BTreeSet::new();
+
+    for additional_path in cfg.extra_src_paths.iter() {
+        let full_additional_path = script_dir.join(additional_path);
+        let (walk_root, pattern) = if is_glob(additional_path) {
+            split_glob(&full_additional_path)
+        } else {
+            (full_additional_path, String::new())
+        };
+        let matcher = if pattern.is_empty() {
+            None
+        } else {
+            Some(
+                globset::GlobBuilder::new(&pattern)
+                    .literal_separator(true)
+                    .build()
+                    .context(format!(
+                        "Invalid extra_src_paths pattern {:?}",
+                        additional_path
+                    ))?
+                    .compile_matcher(),
+            )
+        };
+
+        debug!(
+            "Scanning additional path {:?}, pattern {:?}",
+            walk_root, pattern
+        );
+
+        // The walker does not filter its root, which could be excluded too, e.g. "target".
+        if walk_root.starts_with(script_dir)
+            && excludes
+                .matched_path_or_any_parents(&walk_root, walk_root.is_dir())
+                .is_ignore()
+        {
+            debug!("Skipping excluded path {:?}", walk_root);
+            continue;
+        }
+
+        let excludes = excludes.clone();
+        let use_gitignore = cfg.extra_src_gitignore;
+        let walker = ignore::WalkBuilder::new(&walk_root)
+            .standard_filters(false)
+            .git_ignore(use_gitignore)
+            .git_exclude(use_gitignore)
+            .ignore(use_gitignore)
+            .parents(use_gitignore)
+            .require_git(false)
+            .follow_links(true)
+            .filter_entry(move |entry| {
+                if use_gitignore && entry.file_name() == ".git" {
+                    return false;
+                }
+                let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+                !excludes.matched(entry.path(), is_dir).is_ignore()
+            })
+            .build();
+
+        let mut num_scanned = 0;
+        for entry_res in walker {
+            let entry = match entry_res {
+                Ok(entry) => entry,
+                Err(e) => {
+                    debug!("Skipping directory entry: {:?}", e);
+                    continue;
+                }
+            };
+            if !entry.file_type().map(|t| t.is_file()).unwrap_or(false) {
+                continue;
+            }
+
+            num_scanned += 1;
+            if num_scanned > max_files {
+                return Err(anyhow!(
+                    "Too many files (more than {}) under extra_src_paths entry {:?}, consider narrowing it down with a pattern or extra_src_exclude",
+                    max_files,
+                    additional_path
+                ));
+            }
+
+            if let Some(matcher) = &matcher {
+                let rel_path = entry
+                    .path()
+                    .strip_prefix(&walk_root)
+                    .unwrap_or(entry.path());
+                if !matcher.is_match(rel_path) {
+                    continue;
+                }
+            }
+            files.insert(entry.into_path());
+        }
+    }
+
+    Ok(files.into_iter().collect())
+}
+
+/// Lists every file copied into the cache by `files[].source` entries, in a stable order.
+pub fn collect_file_sources(cfg: &cfg::BuildSpec, script_dir: &Path) -> Vec<PathBuf> {
+    let mut files = vec![];
+    for source in cfg.files.iter().filter_map(|f| f.source.as_ref()) {
+        for entry in walkdir::WalkDir::new(script_dir.join(source))
+            .follow_links(true)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_map(|e| e.ok())
+        {
+            if entry.file_type().is_file() {
+                files.push(entry.into_path());
+            }
+        }
+    }
+    files
+}
+
+/// Copies a file or a directory referenced by `files[].source` to `rel_path` in the cache. Like any
+/// other file written to the cache, every copied entry must stay inside of it.
+pub fn copy_file_source(source: &Path, cache_path: &Path, rel_path: &Path) -> Result<()> {
+    debug!("Copying {:?} to {:?} in {:?}", source, rel_path, cache_path);
+    for entry in walkdir::WalkDir::new(source) {
+        let entry = entry.context(format!("Cannot read files source {:?}", source))?;
+        let target = common::cache_file_path(
+            cache_path,
+            &rel_path.join(entry.path().strip_prefix(source)?),
+        )?;
+        let file_type = entry.file_type();
+        if file_type.is_dir() {
+            std::fs::create_dir_all(&target)
+                .context(format!("Cannot create directory {:?}", target))?;
+        } else if file_type.is_symlink() {
+            let link = std::fs::read_link(entry.path())?;
+            std::os::unix::fs::symlink(&link, &target)
+                .context(format!("Cannot create symlink {:?}", target))?;
+        } else {
+            std::fs::copy(entry.path(), &target).context(format!(
+                "Cannot copy {:?} to {:?}",
+                entry.path(),
+                target
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+/// Copies the files matched by `extra_src_paths` into the cache directory, at the same locations
+/// relative to the script directory.
+pub fn mirror_extra_src_files(
+    cfg: &cfg::BuildSpec,
+    script_dir: &Path,
+    cache_path: &Path,
+) -> Result<()> {
+    for path in collect_extra_src_files(cfg, script_dir)? {
+        // Entries like "../lib" are matched as "<script_dir>/../lib", the prefix alone does not
+        // keep them inside the script directory.
+        let rel_path = path
+            .strip_prefix(script_dir)
+            .ok()
+            .and_then(|rel_path| common::normalize_relative_path(rel_path).ok())
+            .ok_or_else(|| {
+                anyhow!(
+                    "Cannot copy {:?} into the cache directory, as it is outside of the script directory {:?}",
+                    path,
+                    script_dir
+                )
+            })?;
+        let target = common::cache_file_path(cache_path, &rel_path)?;
+        debug!("Copying {:?} to {:?}", path, target);
+        std::fs::copy(&path, &target).context(format!("Cannot copy {:?} to {:?}", path, target))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(cfg: &str) -> cfg::BuildSpec {
+        serde_yaml::from_str(&format!("script_src: main.sh\n{}", cfg)).unwrap()
+    }
+
+    // Creates the files, with paths relative to `dir`.
+    fn create_files(dir: &Path, paths: &[&str]) {
+        for path in paths {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"data").unwrap();
+        }
+    }
+
+    // Collects files relative to `dir`.
+    fn collect(cfg: &str, dir: &Path) -> Vec<String> {
+        collect_extra_src_files(&spec(cfg), dir)
+            .unwrap()
+            .iter()
+            .map(|path| {
+                path.strip_prefix(dir)
+                    .unwrap()
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn split_glob_separates_walk_root() {
+        assert_eq!(
+            split_glob(Path::new("/s/src/**/*.rs")),
+            (PathBuf::from("/s/src"), "**/*.rs".to_string())
+        );
+        assert_eq!(
+            split_glob(Path::new("/s/./lib/*.{c,h}")),
+            (PathBuf::from("/s/lib"), "*.{c,h}".to_string())
+        );
+    }
+
+    #[test]
+    fn collect_extra_src_files_matches_globs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        create_files(dir, &["a.rs", "src/b.rs", "src/sub/c.rs", "src/d.txt"]);
+
+        assert_eq!(
+            collect("extra_src_paths: [\"src/*.rs\"]", dir),
+            vec!["src/b.rs"]
+        );
+        assert_eq!(
+            collect("extra_src_paths: [\"src/**/*.rs\"]", dir),
+            vec!["src/b.rs", "src/sub/c.rs"]
+        );
+        assert_eq!(
+            collect("extra_src_paths: [\"**/*.rs\"]", dir),
+            vec!["a.rs", "src/b.rs", "src/sub/c.rs"]
+        );
+        assert_eq!(
+            collect("extra_src_paths: [src]", dir),
+            vec!["src/b.rs", "src/d.txt", "src/sub/c.rs"]
+        );
+    }
+
+    #[test]
+    fn collect_extra_src_files_skips_excluded_directories() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        create_files(
+            dir,
+            &["src/a.rs", "target/x.rs", "src/target/y.rs", "notes/target"],
+        );
+
+        assert_eq!(
+            collect(
+                "extra_src_paths: [src, target, notes]\nextra_src_exclude: [target/]",
+                dir
+            ),
+            vec!["notes/target", "src/a.rs"]
+        );
+    }
+
+    #[test]
+    fn collect_extra_src_files_fails_on_too_many_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        create_files(dir, &["src/a.rs", "src/b.rs", "src/c.rs"]);
+
+        let cfg = spec("extra_src_paths: [src]");
+        assert_eq!(
+            collect_extra_src_files_with_limit(&cfg, dir, 3)
+                .unwrap()
+                .len(),
+            3
+        );
+        let err = collect_extra_src_files_with_limit(&cfg, dir, 2).unwrap_err();
+        assert!(format!("{:#}", err).contains("Too many files (more than 2)"));
+    }
+
+    fn build_spec(extra_src_paths: &str) -> cfg::BuildSpec {
+        serde_yaml::from_str(&format!(
+            "script_src: main.sh\nextra_src_paths: {}\ncopy_extra_src_paths: true",
+            extra_src_paths
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn mirror_extra_src_files_copies_inside_cache_dir() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let script_dir = dir.join("proj");
+        let cache_dir = dir.join("cache");
+        std::fs::create_dir_all(script_dir.join("lib")).unwrap();
+        std::fs::create_dir_all(&cache_dir).unwrap();
+        std::fs::write(script_dir.join("lib/x.txt"), b"data").unwrap();
+
+        mirror_extra_src_files(&build_spec("[lib]"), &script_dir, &cache_dir).unwrap();
+        assert_eq!(std::fs::read(cache_dir.join("lib/x.txt")).unwrap(), b"data");
+    }
+
+    #[test]
+    fn mirror_extra_src_files_rejects_symlink_escape() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let script_dir = dir.join("proj");
+        let cache_dir = dir.join("cache");
+        let outside = dir.join("outside");
+        std::fs::create_dir_all(script_dir.join("lib")).unwrap();
+        std::fs::create_dir_all(&cache_dir).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::write(script_dir.join("lib/x.txt"), b"data").unwrap();
+        std::os::unix::fs::symlink(&outside, cache_dir.join("lib")).unwrap();
+
+        assert!(mirror_extra_src_files(&build_spec("[lib]"), &script_dir, &cache_dir).is_err());
+        assert!(!outside.join("x.txt").exists());
+    }
+
+    #[test]
+    fn copy_file_source_replaces_symlinks_in_cache_dir() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let source = dir.join("proj/data");
+        let cache_dir = dir.join("cache");
+        let outside = dir.join("outside");
+        std::fs::create_dir_all(source.join("sub")).unwrap();
+        std::fs::create_dir_all(cache_dir.join("data")).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::write(source.join("sub/x.txt"), b"data").unwrap();
+        std::os::unix::fs::symlink(&outside, cache_dir.join("data/sub")).unwrap();
+
+        copy_file_source(&source, &cache_dir, Path::new("data")).unwrap();
+        assert_eq!(
+            std::fs::read(cache_dir.join("data/sub/x.txt")).unwrap(),
+            b"data"
+        );
+        assert!(!outside.join("x.txt").exists());
+        assert!(copy_file_source(&source, &cache_dir, Path::new("../data")).is_err());
+        assert!(!dir.join("data").exists());
+    }
+
+    #[test]
+    fn mirror_extra_src_files_rejects_paths_outside_script_dir() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let script_dir = dir.join("proj/s");
+        let cache_dir = dir.join("cache/proj/s/b.sh");
+        std::fs::create_dir_all(&script_dir).unwrap();
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+        std::fs::create_dir_all(&cache_dir).unwrap();
+        std::fs::write(dir.join("lib/x.txt"), b"data").unwrap();
+
+        for extra_src_paths in ["[../../lib]", "[\"../../lib/*.txt\"]", "[./../../lib]"] {
+            assert!(
+                mirror_extra_src_files(&build_spec(extra_src_paths), &script_dir, &cache_dir)
+                    .is_err(),
+                "{} should be rejected",
+                extra_src_paths
+            );
+        }
+        assert!(!dir.join("cache/lib").exists());
+        assert!(!dir.join("cache/proj/lib").exists());
+    }
+}
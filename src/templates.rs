@@ -12,11 +12,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::common;
 use crate::editor;
-use crate::opt::TemplatesCommand;
+use crate::opt::{OutputFormat, TemplatesCommand};
 use anyhow::{anyhow, Context, Result};
 use include_dir::Dir;
 use log::debug;
+use serde_derive::Serialize;
 use std::collections::BTreeMap;
 use std::fmt::Debug;
 use std::fs::File;
@@ -25,7 +27,8 @@ use std::path::{Path, PathBuf};
 
 const TEMPLATES: Dir = include_dir!("./data/templates/");
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
 enum Source {
     BuiltIn,
     Custom,
@@ -123,37 +126,33 @@ fn get_custom_templates() -> Result<TemplateMap> {
     Ok(templates)
 }
 
-fn print_ascii_table(rows: &[Vec<String>]) {
-    use prettytable::{format, row, Cell, Row, Table};
-
-    let mut table = Table::new();
-    let format = format::FormatBuilder::new()
-        .column_separator('|')
-        .borders('|')
-        .separators(
-            &[format::LinePosition::Top, format::LinePosition::Bottom],
-            format::LineSeparator::new('-', '+', '+', '+'),
-        )
-        .separators(
-            &[format::LinePosition::Title],
-            format::LineSeparator::new('=', '+', '+', '+'),
-        )
-        .padding(1, 1)
-        .build();
-    table.set_format(format);
-    table.set_titles(row!["Template Name", "Custom", "Extension"]);
-    for table_row in rows {
-        table.add_row(Row::new(table_row.iter().map(|s| Cell::new(s)).collect()));
-    }
-    table.printstd();
-}
-
 fn get_templates() -> Result<TemplateMap> {
     let mut templates = get_built_in_templates()?;
     templates.append(&mut get_custom_templates()?);
     Ok(templates)
 }
 
+/// Returns the contents of the template, custom templates take precedence over built-in ones.
+pub fn get_template_contents(name: &str) -> Result<String> {
+    get_templates()?
+        .remove(name)
+        .map(|template| template.contents)
+        .ok_or_else(|| {
+            anyhow!(
+                "Template {:?} is not found, see 'scriptisto template ls' for available templates",
+                name
+            )
+        })
+}
+
+/// Returns file names and contents of all templates, custom ones taking precedence.
+pub fn get_template_files() -> Result<Vec<(String, String)>> {
+    Ok(get_templates()?
+        .into_values()
+        .map(|template| (template.filename, template.contents))
+        .collect())
+}
+
 fn filename_extension(filename: &str) -> String {
     Path::new(filename)
         .extension()
@@ -161,6 +160,27 @@ fn filename_extension(filename: &str) -> String {
         .unwrap_or_default()
 }
 
+#[derive(Debug, Serialize)]
+struct TemplateInfo<'a> {
+    name: &'a str,
+    source: &'a Source,
+    filename: &'a str,
+    extension: String,
+}
+
+fn print_templates_serialized(templates: &TemplateMap, format: OutputFormat) -> Result<()> {
+    let infos: Vec<_> = templates
+        .iter()
+        .map(|(k, v)| TemplateInfo {
+            name: k,
+            source: &v.source,
+            filename: &v.filename,
+            extension: filename_extension(&v.filename),
+        })
+        .collect();
+    common::print_serialized(format, &infos)
+}
+
 fn print_templates(templates: &TemplateMap) {
     let table: Vec<_> = templates
         .iter()
@@ -177,7 +197,7 @@ fn print_templates(templates: &TemplateMap) {
         })
         .collect();
 
-    print_ascii_table(&table);
+    common::print_ascii_table(&["Template Name", "Custom", "Extension"], &table);
 }
 
 fn template_not_found(name: &str, templates: &TemplateMap) -> ! {
@@ -290,10 +310,13 @@ pub fn command_template(cmd: TemplatesCommand) -> Result<()> {
     let templates = get_templates()?;
 
     match cmd {
-        TemplatesCommand::List {} => {
-            print_templates(&templates);
-            Ok(())
-        }
+        TemplatesCommand::List { format } => match format.unwrap_or_default() {
+            OutputFormat::Table => {
+                print_templates(&templates);
+                Ok(())
+            }
+            format => print_templates_serialized(&templates, format),
+        },
         TemplatesCommand::Import { file } => command_template_import(&file),
         TemplatesCommand::Edit { template_name } => command_template_edit(template_name),
         TemplatesCommand::Remove { template_name } => command_template_rm(template_name),